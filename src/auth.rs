@@ -0,0 +1,225 @@
+use crate::{config::Config, protocol::Envelope};
+use anyhow::{bail, Result};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Serialize;
+use serde_json::Value;
+
+const NONCE_LEN: usize = 32;
+
+/// Cluster secret of this node, used for the challenge/response handshake.
+#[derive(Debug, Clone)]
+pub struct ClusterAuth {
+    key: hmac::Key,
+    pub node_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl ClusterAuth {
+    pub fn new(secret: &str, node_name: String) -> ClusterAuth {
+        ClusterAuth {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            node_name,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Option<ClusterAuth> {
+        config
+            .cluster_secret
+            .as_ref()
+            .map(|secret| ClusterAuth::new(secret, config.config_metadata.name.clone()))
+    }
+
+    /// Proof sent by the listener along with its own nonce.
+    pub fn server_proof(&self, client_nonce: &str, server_nonce: &str) -> String {
+        self.sign(&["server", client_nonce, server_nonce])
+    }
+
+    /// Proof sent by the connecting node to finish the handshake.
+    pub fn client_proof(&self, client_nonce: &str, server_nonce: &str) -> String {
+        self.sign(&["client", client_nonce, server_nonce])
+    }
+
+    pub fn verify_server_proof(&self, client_nonce: &str, server_nonce: &str, proof: &str) -> bool {
+        self.verify(&["server", client_nonce, server_nonce], proof)
+    }
+
+    pub fn verify_client_proof(&self, client_nonce: &str, server_nonce: &str, proof: &str) -> bool {
+        self.verify(&["client", client_nonce, server_nonce], proof)
+    }
+
+    /// Derives the per-connection keys both sides use to sign messages after the handshake.
+    pub fn session(&self, client_nonce: &str, server_nonce: &str, role: Role) -> Session {
+        let derive = |label: &str| {
+            let tag = hmac::sign(&self.key, &message(&[label, client_nonce, server_nonce]));
+            hmac::Key::new(hmac::HMAC_SHA256, tag.as_ref())
+        };
+        let (client_to_server, server_to_client) = (derive("c2s"), derive("s2c"));
+
+        match role {
+            Role::Client => Session::new(client_to_server, server_to_client),
+            Role::Server => Session::new(server_to_client, client_to_server),
+        }
+    }
+
+    fn sign(&self, parts: &[&str]) -> String {
+        to_hex(hmac::sign(&self.key, &message(parts)).as_ref())
+    }
+
+    fn verify(&self, parts: &[&str], proof: &str) -> bool {
+        match from_hex(proof) {
+            Some(tag) => hmac::verify(&self.key, &message(parts), &tag).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Signs outgoing and verifies incoming envelopes of one authenticated connection.
+#[derive(Debug)]
+pub struct Session {
+    send: hmac::Key,
+    recv: hmac::Key,
+    last_received_id: Option<u64>,
+}
+
+impl Session {
+    fn new(send: hmac::Key, recv: hmac::Key) -> Session {
+        Session {
+            send,
+            recv,
+            last_received_id: None,
+        }
+    }
+
+    pub fn seal<T: Serialize>(&self, envelope: Envelope<T>) -> Result<Envelope<Value>> {
+        let body = serde_json::to_value(&envelope.body)?;
+        let tag = hmac::sign(
+            &self.send,
+            &signed_bytes(envelope.version, envelope.id, &body)?,
+        );
+
+        Ok(Envelope {
+            version: envelope.version,
+            id: envelope.id,
+            body,
+            mac: Some(to_hex(tag.as_ref())),
+        })
+    }
+
+    /// Checks the signature and rejects replayed (non-increasing) ids.
+    pub fn open(&mut self, envelope: &Envelope<Value>) -> Result<()> {
+        let Some(tag) = envelope.mac.as_deref().and_then(from_hex) else {
+            bail!("Missing message signature");
+        };
+        let bytes = signed_bytes(envelope.version, envelope.id, &envelope.body)?;
+        if hmac::verify(&self.recv, &bytes, &tag).is_err() {
+            bail!("Invalid message signature");
+        }
+
+        if self
+            .last_received_id
+            .is_some_and(|last| envelope.id <= last)
+        {
+            bail!("Replayed message {}", envelope.id);
+        }
+        self.last_received_id = Some(envelope.id);
+        Ok(())
+    }
+}
+
+pub fn nonce() -> Result<String> {
+    let mut bytes = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("Failed to generate nonce"))?;
+    Ok(to_hex(&bytes))
+}
+
+/// `serde_json::Value` keeps object keys sorted, so both sides serialize the body identically.
+fn signed_bytes(version: u16, id: u64, body: &Value) -> Result<Vec<u8>> {
+    let mut bytes = format!("{version}:{id}:").into_bytes();
+    serde_json::to_writer(&mut bytes, body)?;
+    Ok(bytes)
+}
+
+fn message(parts: &[&str]) -> Vec<u8> {
+    parts.join(":").into_bytes()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Request;
+
+    #[test]
+    fn test_handshake_and_signed_messages() {
+        let client = ClusterAuth::new("hunter2", "a".to_string());
+        let server = ClusterAuth::new("hunter2", "b".to_string());
+        let (client_nonce, server_nonce) = (nonce().unwrap(), nonce().unwrap());
+
+        let proof = server.server_proof(&client_nonce, &server_nonce);
+        assert!(client.verify_server_proof(&client_nonce, &server_nonce, &proof));
+        let proof = client.client_proof(&client_nonce, &server_nonce);
+        assert!(server.verify_client_proof(&client_nonce, &server_nonce, &proof));
+
+        let client_session = client.session(&client_nonce, &server_nonce, Role::Client);
+        let mut server_session = server.session(&client_nonce, &server_nonce, Role::Server);
+
+        let sealed = client_session
+            .seal(Envelope::new(3, Request::GetConfig))
+            .unwrap();
+        assert!(server_session.open(&sealed).is_ok());
+        // Same message again is a replay
+        assert!(server_session.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_wrong_secret_is_rejected() {
+        let client = ClusterAuth::new("hunter2", "a".to_string());
+        let server = ClusterAuth::new("*******", "b".to_string());
+        let (client_nonce, server_nonce) = (nonce().unwrap(), nonce().unwrap());
+
+        let proof = client.client_proof(&client_nonce, &server_nonce);
+        assert!(!server.verify_client_proof(&client_nonce, &server_nonce, &proof));
+    }
+
+    #[test]
+    fn test_tampered_message_is_rejected() {
+        let auth = ClusterAuth::new("hunter2", "a".to_string());
+        let client_session = auth.session("c", "s", Role::Client);
+        let mut server_session = auth.session("c", "s", Role::Server);
+
+        let mut sealed = client_session
+            .seal(Envelope::new(1, Request::Ping))
+            .unwrap();
+        sealed.body = serde_json::to_value(Request::GetConfig).unwrap();
+        assert!(server_session.open(&sealed).is_err());
+
+        // A response signed by the server can't be reflected back as a request
+        let reflected = server_session
+            .seal(Envelope::new(2, Request::Ping))
+            .unwrap();
+        assert!(server_session.open(&reflected).is_err());
+    }
+}
@@ -1,14 +1,54 @@
+use crate::lease::{LEASE_DURATION, LEASE_REQUEST_TIMEOUT};
+use crate::log;
 use crate::timestamp::Timestamp;
-use serde::{Deserialize, Serialize};
+use chrono::{NaiveTime, Timelike};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    time::Duration,
+};
+
+/// Name of the service run from the top-level `execution`.
+pub const DEFAULT_SERVICE: &str = "default";
+
+pub fn default_service() -> String {
+    DEFAULT_SERVICE.to_string()
+}
+
+pub fn is_default_service(service: &str) -> bool {
+    service == DEFAULT_SERVICE
+}
 
 // Config
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ProviderNode {
     pub name: String,
     pub ip: String,
     pub port: u32,
     pub priority: u32,
     pub last_updated: Timestamp,
+    #[serde(default, skip_serializing_if = "NodeRole::is_provider")]
+    pub role: NodeRole,
+    /// Breaks ties between nodes of the same priority with `tie_breaker: id`, lowest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRole {
+    /// May run the process
+    #[default]
+    Provider,
+    /// Takes part in liveness checks, quorum and lease votes, but never runs the process
+    Witness,
+}
+
+impl NodeRole {
+    pub fn is_provider(&self) -> bool {
+        *self == NodeRole::Provider
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -19,18 +59,635 @@ pub struct ConfigMetadata {
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ExecutionInstructions {
+    /// Command line, split into words like a shell would: quotes and backslashes work, pipes,
+    /// redirections and variables only with `shell`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub instructions: String,
+    /// Program and arguments, instead of `instructions`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub argv: Vec<String>,
+    /// Runs `instructions` with `sh -c`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shell: bool,
+    /// Added to the environment the node was started with
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// File of `KEY=VALUE` lines, read whenever the process starts. `env` wins over it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_directory: Option<String>,
     pub last_updated: Timestamp,
+    #[serde(default, skip_serializing_if = "RestartPolicy::is_default")]
+    pub restart: RestartPolicy,
+    #[serde(default, skip_serializing_if = "StopPolicy::is_default")]
+    pub stop: StopPolicy,
+    #[serde(default, skip_serializing_if = "OutputConfig::is_default")]
+    pub output: OutputConfig,
+}
+
+impl ExecutionInstructions {
+    /// Program and arguments to run.
+    pub fn command(&self) -> Result<Vec<String>, String> {
+        if !self.argv.is_empty() {
+            if !self.instructions.is_empty() {
+                return Err("set either `instructions` or `argv`, not both".to_string());
+            }
+            if self.shell {
+                return Err("`shell` runs `instructions`, not `argv`".to_string());
+            }
+            return Ok(self.argv.clone());
+        }
+        if self.instructions.trim().is_empty() {
+            return Err("nothing to run, set `instructions` or `argv`".to_string());
+        }
+        if self.shell {
+            return Ok(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                self.instructions.clone(),
+            ]);
+        }
+        match shlex::split(&self.instructions) {
+            Some(args) if args.is_empty() => Err("instructions contain no command".to_string()),
+            Some(args) => Ok(args),
+            None => Err(format!("unbalanced quotes in \"{}\"", self.instructions)),
+        }
+    }
+}
+
+/// How the process is restarted after it exits, and when to give up and let another node run it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RestartPolicy {
+    /// Restarts in a row before handing the process over to another node
+    pub max_restarts: u32,
+    /// Wait before the first restart, doubled for every further one
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// A process that stayed up this long starts over with a fresh count
+    pub reset_after_secs: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            reset_after_secs: 60,
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == RestartPolicy::default()
+    }
+
+    /// Wait before the `restart`th restart in a row, starting at 1
+    pub fn backoff(&self, restart: u32) -> Duration {
+        let factor = 1u64 << restart.saturating_sub(1).min(32);
+        Duration::from_millis(
+            self.initial_backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+
+    pub fn reset_after(&self) -> Duration {
+        Duration::from_secs(self.reset_after_secs)
+    }
+}
+
+/// A process the cluster keeps running on one node at a time, independently of the others.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Service {
+    pub name: String,
+    pub execution: ExecutionInstructions,
+    /// Nodes that may run the service, with their priority for it. Every provider, with its
+    /// own `priority`, if empty.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub priorities: BTreeMap<String, u32>,
+}
+
+impl Service {
+    /// Priority of `node` for this service, `None` if it may not run it.
+    pub fn priority(&self, node: &ProviderNode) -> Option<u32> {
+        if !node.role.is_provider() {
+            return None;
+        }
+        if self.priorities.is_empty() {
+            return Some(node.priority);
+        }
+        self.priorities.get(&node.name).copied()
+    }
+}
+
+/// Where the output of the process goes. By default it shares the node's stdout and stderr.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// File the process's stdout is written to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    /// File the process's stderr is written to, may be the same as `stdout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// A file is rotated before it grows past this
+    pub max_size_bytes: u64,
+    /// A file is also rotated once it's been written to for this long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotate_every_secs: Option<u64>,
+    /// Rotated files kept, from `<file>.1` (newest) to `<file>.<keep>`
+    pub keep: u32,
+    /// Also print every line on the node's own stdout or stderr, after `prefix`
+    pub forward: bool,
+    /// The service's name in brackets unless set, e.g. `[worker]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            stdout: None,
+            stderr: None,
+            max_size_bytes: 10 * 1024 * 1024,
+            rotate_every_secs: None,
+            keep: 5,
+            forward: false,
+            prefix: None,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn is_default(&self) -> bool {
+        *self == OutputConfig::default()
+    }
+
+    pub fn rotate_every(&self) -> Option<Duration> {
+        self.rotate_every_secs.map(Duration::from_secs)
+    }
+
+    /// What lines of `service` are printed after, if they're forwarded
+    pub fn forward_prefix(&self, service: &str) -> Option<String> {
+        self.forward.then(|| {
+            self.prefix
+                .clone()
+                .unwrap_or_else(|| format!("[{}]", service))
+        })
+    }
+}
+
+/// How the process is stopped when the node steps down.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct StopPolicy {
+    /// Sent to the whole process group first
+    pub signal: Signal,
+    /// How long the process gets to exit before it's killed with `SIGKILL`. Keep it well
+    /// under 15s, or nodes taking over stop waiting for the handover and wait for the lease.
+    pub grace_period_ms: u64,
+}
+
+impl Default for StopPolicy {
+    fn default() -> Self {
+        StopPolicy {
+            signal: Signal(libc::SIGTERM),
+            grace_period_ms: 10_000,
+        }
+    }
+}
+
+impl StopPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == StopPolicy::default()
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_millis(self.grace_period_ms)
+    }
+}
+
+const SIGNALS: [(&str, i32); 7] = [
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGTERM", libc::SIGTERM),
+];
+
+/// A Unix signal, written by name with or without the `SIG` prefix (`SIGTERM`, `int`).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Signal(pub i32);
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SIGNALS.iter().find(|(_, number)| *number == self.0) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => write!(f, "signal {}", self.0),
+        }
+    }
+}
+
+impl std::str::FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|(known, _)| known[3..] == *name)
+            .map(|(_, number)| Signal(*number))
+            .ok_or_else(|| format!("unknown signal \"{}\"", s))
+    }
+}
+
+impl Serialize for Signal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Signal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// How nodes of the same priority are ordered in elections. Names come last either way, so the
+/// order is always total.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TieBreaker {
+    /// Lowest name first
+    #[default]
+    Name,
+    /// Lowest `id` first, nodes without one after those with one
+    Id,
+}
+
+impl TieBreaker {
+    pub fn is_default(&self) -> bool {
+        *self == TieBreaker::Name
+    }
+}
+
+impl fmt::Display for TieBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TieBreaker::Name => write!(f, "name"),
+            TieBreaker::Id => write!(f, "id"),
+        }
+    }
+}
+
+/// Paths to this node's TLS material. When set, every peer connection uses mutual TLS and
+/// peers must present a certificate issued for their node name.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    pub ca: String,
+}
+
+/// How quickly peers are declared down and counted again. Local to each node.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FailureDetection {
+    /// Time between two heartbeats
+    pub heartbeat_interval_ms: u64,
+    /// How long a probe waits for an answer
+    pub probe_timeout_ms: u64,
+    /// Consecutive failed probes (direct and indirect) before a node is suspected, which
+    /// makes it dead unless it refutes in time
+    pub failures_before_dead: u32,
+    /// Consecutive heartbeats a node that was down has to look alive before elections count
+    /// it again
+    pub successes_before_alive: u32,
+    /// A node that went down less than this long ago isn't counted as alive again yet
+    pub flap_window_ms: u64,
+    /// Suspect nodes by how unusual their silence is (phi accrual) rather than by counting
+    /// failed probes. Around 8 suits most networks; lower reacts faster, higher tolerates
+    /// more jitter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phi_threshold: Option<f64>,
+}
+
+impl Default for FailureDetection {
+    fn default() -> Self {
+        FailureDetection {
+            heartbeat_interval_ms: 1000,
+            probe_timeout_ms: 2000,
+            failures_before_dead: 1,
+            successes_before_alive: 1,
+            flap_window_ms: 0,
+            phi_threshold: None,
+        }
+    }
+}
+
+impl FailureDetection {
+    pub fn is_default(&self) -> bool {
+        *self == FailureDetection::default()
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms.max(1))
+    }
+
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_ms.max(1))
+    }
+
+    pub fn flap_window(&self) -> Duration {
+        Duration::from_millis(self.flap_window_ms)
+    }
+
+    /// The longest an active node can go without renewing its leases: a heartbeat, a probe,
+    /// an indirect probe and the lease requests, which go out for all services at once.
+    pub fn renewal_interval(&self) -> Duration {
+        self.heartbeat_interval() + 3 * self.probe_timeout() + LEASE_REQUEST_TIMEOUT
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PreemptionMode {
+    /// A node that outranks the active one takes over as soon as it's available
+    #[default]
+    Immediate,
+    /// The active node keeps the process until it fails or steps down
+    Never,
+    /// Take over once the returning node has been up for `healthy_for_secs`, and/or only
+    /// during the maintenance `window`
+    Delayed,
+}
+
+/// What happens when a node that outranks the active one becomes available. The same for the
+/// whole cluster; a newer config pulled from a peer brings it along.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Preemption {
+    pub mode: PreemptionMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthy_for_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<MaintenanceWindow>,
+}
+
+impl Preemption {
+    pub fn is_default(&self) -> bool {
+        *self == Preemption::default()
+    }
+}
+
+/// A daily time range in UTC, written `HH:MM-HH:MM`. It may span midnight (`23:00-01:00`).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MaintenanceWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start.hour(),
+            self.start.minute(),
+            self.end.hour(),
+            self.end.minute()
+        )
+    }
+}
+
+impl std::str::FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid window \"{}\", expected HH:MM-HH:MM", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse =
+            |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(MaintenanceWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl Serialize for MaintenanceWindow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for MaintenanceWindow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A way to make sure a node that went silent while running the process really stopped, before
+/// another node starts it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FenceAction {
+    /// Runs `command` with `sh -c`. The node is passed in `P2P_FENCE_NODE` and
+    /// `P2P_FENCE_ADDRESS`, the service in `P2P_FENCE_SERVICE`; exiting with 0 means it's
+    /// fenced.
+    Script {
+        command: String,
+        #[serde(default = "default_fence_timeout")]
+        timeout_secs: u64,
+    },
+    /// Calls `url`, with `{node}` replaced by the node's name and `{service}` by the service's.
+    /// Any 2xx answer means it's fenced.
+    Http {
+        url: String,
+        #[serde(default = "default_fence_method")]
+        method: String,
+        #[serde(default = "default_fence_timeout")]
+        timeout_secs: u64,
+    },
+    /// The node itself has to confirm it stopped the process.
+    Acknowledgement,
+}
+
+fn default_fence_timeout() -> u64 {
+    30
+}
+
+fn default_fence_method() -> String {
+    "POST".to_string()
+}
+
+impl fmt::Display for FenceAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FenceAction::Script { command, .. } => write!(f, "Fencing script \"{}\"", command),
+            FenceAction::Http { url, method, .. } => write!(f, "Fencing call {} {}", method, url),
+            FenceAction::Acknowledgement => write!(f, "Acknowledgement"),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub nodes: Vec<ProviderNode>,
     pub config_metadata: ConfigMetadata,
-    pub execution: ExecutionInstructions,
+    /// The `default` service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution: Option<ExecutionInstructions>,
+    /// More services, each with an active node of its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<Service>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Pre-shared secret every node of the cluster must prove knowledge of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_secret: Option<String>,
+    /// Only run the process while a strict majority of `nodes` is reachable, so the minority
+    /// side of a network partition stays passive.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quorum: bool,
+    /// Address the listener binds to, `0.0.0.0` by default. Use `[::]` for IPv6.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_address: Option<String>,
+    #[serde(default, skip_serializing_if = "FailureDetection::is_default")]
+    pub failure_detection: FailureDetection,
+    #[serde(default, skip_serializing_if = "Preemption::is_default")]
+    pub preemption: Preemption,
+    #[serde(default, skip_serializing_if = "TieBreaker::is_default")]
+    pub tie_breaker: TieBreaker,
+    /// Run before taking over from a node that went silent while active. Local to each node.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fencing: Vec<FenceAction>,
 }
 
 impl Config {
+    /// YAML that is safe to hand out to peers: local-only settings and secrets are removed.
+    pub fn to_shared_yaml(&self) -> Result<String, serde_yaml::Error> {
+        let mut shared = self.clone();
+        shared.tls = None;
+        shared.cluster_secret = None;
+        shared.bind_address = None;
+        shared.failure_detection = FailureDetection::default();
+        shared.fencing = vec![];
+        serde_yaml::to_string(&shared)
+    }
+
+    /// Every service, the `default` one from `execution` first.
+    pub fn services(&self) -> Vec<Service> {
+        let default = self.execution.clone().map(|execution| Service {
+            name: default_service(),
+            execution,
+            priorities: BTreeMap::new(),
+        });
+        default.into_iter().chain(self.services.clone()).collect()
+    }
+
+    pub fn service(&self, name: &str) -> Option<Service> {
+        self.services()
+            .into_iter()
+            .find(|service| service.name == name)
+    }
+
+    /// Checks what serde can't: service names must be unique, `default` is taken by
+    /// `execution`, and every service needs something to run.
+    pub fn validate(&self) -> Result<(), String> {
+        for service in self.services() {
+            service
+                .execution
+                .command()
+                .map_err(|e| format!("service \"{}\": {}", service.name, e))?;
+        }
+
+        let mut names = BTreeSet::new();
+        for service in &self.services {
+            if is_default_service(&service.name) {
+                return Err(format!(
+                    "service \"{}\" is reserved for `execution`",
+                    service.name
+                ));
+            }
+            if !names.insert(&service.name) {
+                return Err(format!("service \"{}\" is defined twice", service.name));
+            }
+        }
+
+        let renewal = self.failure_detection.renewal_interval();
+        if renewal >= LEASE_DURATION {
+            return Err(format!(
+                "failure_detection lets up to {}ms pass between lease renewals, \
+                 but leases run out after {}s",
+                renewal.as_millis(),
+                LEASE_DURATION.as_secs()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Names of the nodes that can run `service` but share their priority for it with another
+    /// one, grouped by priority. The tie breaker decides between them.
+    pub fn priority_ties(&self, service: &Service) -> Vec<Vec<String>> {
+        let mut by_priority: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        for node in &self.nodes {
+            if let Some(priority) = service.priority(node) {
+                by_priority
+                    .entry(priority)
+                    .or_default()
+                    .push(node.name.clone());
+            }
+        }
+        by_priority
+            .into_values()
+            .rev()
+            .filter(|names| names.len() > 1)
+            .collect()
+    }
+
+    /// Points out nodes whose order is left to the tie breaker. Done at startup and when the
+    /// config is reloaded on request, not on every write.
+    pub fn warn_about_ties(&self) {
+        for service in self.services() {
+            for tied in self.priority_ties(&service) {
+                let scope = if is_default_service(&service.name) {
+                    String::new()
+                } else {
+                    format!(" for \"{}\"", service.name)
+                };
+                log!(
+                    "Warning: {} share the same priority{}, ordering them by {}",
+                    tied.iter()
+                        .map(|name| format!("\"{}\"", name))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    scope,
+                    self.tie_breaker
+                );
+            }
+        }
+    }
+
     pub fn write(&self) {
         let config_path = std::env::var("P2P_CONFIG_PATH")
             .unwrap_or_else(|_| "p2p-failover.config.yaml".to_string());
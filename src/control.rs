@@ -0,0 +1,224 @@
+use crate::{
+    config::NodeRole,
+    debug,
+    event::EventRecord,
+    log,
+    membership::{MemberState, Preference},
+    protocol::{self, Envelope},
+};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    os::unix::{fs::PermissionsExt, net::UnixStream as StdUnixStream},
+    time::Duration,
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+/// Socket path when `P2P_CONTROL_SOCKET` isn't set.
+pub const DEFAULT_CONTROL_SOCKET: &str = "p2p-failover.sock";
+
+pub fn socket_path() -> String {
+    std::env::var("P2P_CONTROL_SOCKET").unwrap_or_else(|_| DEFAULT_CONTROL_SOCKET.to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    /// Re-read the config file now instead of waiting for the file watcher
+    Reload,
+    /// Stop every service and never run them until `Resume`
+    Drain,
+    /// Run the services here, regardless of priority
+    Promote,
+    /// Hand the services to any other available node
+    Demote,
+    /// Back to electing by priority
+    Resume,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status(Status),
+    Done { message: String },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Running the service
+    Active,
+    Standby,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Status {
+    pub node: String,
+    pub node_role: NodeRole,
+    pub preference: Preference,
+    /// Whether we can reach a majority of the nodes, if quorum mode is on
+    pub quorum: Option<bool>,
+    pub services: Vec<ServiceStatus>,
+    pub peers: Vec<PeerStatus>,
+    /// Most recent events, oldest first
+    #[serde(default)]
+    pub events: Vec<EventRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub role: Role,
+    /// Current election term of the service
+    pub term: u64,
+    pub pid: Option<u32>,
+    /// The node running it, as far as we know
+    pub active: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub name: String,
+    pub address: String,
+    pub priority: u32,
+    pub role: NodeRole,
+    pub alive: bool,
+    pub state: Option<MemberState>,
+    pub preference: Preference,
+    /// Suspicion level from the phi-accrual failure detector, once we've heard from the peer
+    /// a few times
+    #[serde(default)]
+    pub phi: Option<f64>,
+}
+
+/// A request from the control socket, to be answered by whoever owns the node.
+pub struct Command {
+    pub request: ControlRequest,
+    pub reply: oneshot::Sender<ControlResponse>,
+}
+
+/// Listens on the Unix socket at `path` and forwards every request to the returned channel.
+///
+/// The socket is only accessible by the user running the daemon.
+pub fn start_control_socket(path: String) -> Result<mpsc::Receiver<Command>> {
+    if fs::metadata(&path).is_ok() {
+        if StdUnixStream::connect(&path).is_ok() {
+            bail!("Another node is already listening on {}", path);
+        }
+        // Left behind by a node that didn't shut down cleanly
+        fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    debug!("Control socket listening on {}", path);
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log!("Error accepting control connection: {:?}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(handle_connection(stream, tx.clone()));
+        }
+    });
+
+    Ok(rx)
+}
+
+async fn handle_connection(mut stream: UnixStream, commands: mpsc::Sender<Command>) {
+    loop {
+        let request: Envelope<ControlRequest> = match protocol::read_frame_async(&mut stream).await
+        {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Control connection closed: {:#}", e);
+                return;
+            }
+        };
+
+        let (reply, response) = oneshot::channel();
+        let command = Command {
+            request: request.body,
+            reply,
+        };
+        let response = match commands.send(command).await {
+            Ok(()) => response.await.unwrap_or(ControlResponse::Error {
+                message: "the node dropped the request".to_string(),
+            }),
+            Err(_) => ControlResponse::Error {
+                message: "the node is shutting down".to_string(),
+            },
+        };
+
+        let response = Envelope::new(request.id, response);
+        if let Err(e) = protocol::write_frame_async(&mut stream, &response).await {
+            debug!("Error answering control request: {:#}", e);
+            return;
+        }
+    }
+}
+
+/// Sends a single request to the node listening on `path`. Blocks.
+pub fn send(path: &str, request: ControlRequest) -> Result<ControlResponse> {
+    let mut stream = match StdUnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => bail!("Couldn't connect to {}: {} (is the node running?)", path, e),
+    };
+    // Commands are handled between heartbeats, which can take a few seconds. Preference changes
+    // answer once the services they stop are gone, which takes as long as their grace periods
+    // add up to, so they wait as long as it takes.
+    let timeout = match request {
+        ControlRequest::Status | ControlRequest::Reload => Some(Duration::from_secs(15)),
+        _ => None,
+    };
+    stream.set_read_timeout(timeout)?;
+
+    protocol::write_frame(&mut stream, &Envelope::new(1, request))?;
+    let response: Envelope<ControlResponse> = protocol::read_frame(&mut stream)?;
+    Ok(response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests_reach_the_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join("control.sock")
+            .to_string_lossy()
+            .to_string();
+        let mut commands = start_control_socket(path.clone()).unwrap();
+
+        tokio::spawn(async move {
+            while let Some(command) = commands.recv().await {
+                let message = format!("{:?}", command.request);
+                let _ = command.reply.send(ControlResponse::Done { message });
+            }
+        });
+
+        let response = tokio::task::spawn_blocking(move || send(&path, ControlRequest::Drain))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            response,
+            ControlResponse::Done {
+                message: "Drain".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::config::FailureDetection;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Default)]
+struct Peer {
+    /// Whether elections currently count the peer as alive
+    counted: bool,
+    /// Consecutive heartbeats the peer looked alive while not counted
+    streak: u32,
+    down_since: Option<Instant>,
+    up_since: Option<Instant>,
+}
+
+/// Keeps peers that just came back out of elections for a while, so a flaky link doesn't
+/// bounce the process between nodes. Going down is never delayed here; that's up to the
+/// failure detector.
+#[derive(Clone, Default)]
+pub struct Damping {
+    peers: HashMap<String, Peer>,
+}
+
+impl Damping {
+    pub fn new() -> Damping {
+        Damping::default()
+    }
+
+    /// Takes note of whether `name` looks alive this heartbeat. Returns whether it counts as
+    /// alive for elections.
+    pub fn observe(
+        &mut self,
+        name: &str,
+        alive: bool,
+        settings: &FailureDetection,
+        now: Instant,
+    ) -> bool {
+        let peer = self.peers.entry(name.to_string()).or_default();
+        if !alive {
+            if peer.counted {
+                peer.counted = false;
+                peer.down_since = Some(now);
+                peer.up_since = None;
+            }
+            peer.streak = 0;
+            return false;
+        }
+        if peer.counted {
+            return true;
+        }
+
+        peer.streak = peer.streak.saturating_add(1);
+        let flapping = peer
+            .down_since
+            .is_some_and(|since| now.duration_since(since) < settings.flap_window());
+        if peer.streak >= settings.successes_before_alive && !flapping {
+            peer.counted = true;
+            peer.streak = 0;
+            peer.up_since = Some(now);
+        }
+        peer.counted
+    }
+
+    /// How long `name` has been counted as alive without interruption.
+    pub fn up_for(&self, name: &str, now: Instant) -> Option<Duration> {
+        let since = self.peers.get(name)?.up_since?;
+        Some(now.duration_since(since))
+    }
+
+    /// Whether `name` looks alive but isn't counted yet.
+    pub fn is_held_back(&self, name: &str) -> bool {
+        self.peers
+            .get(name)
+            .is_some_and(|peer| !peer.counted && peer.streak > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_count_right_away() {
+        let mut damping = Damping::new();
+        let settings = FailureDetection::default();
+        let now = Instant::now();
+        assert!(damping.observe("b", true, &settings, now));
+        assert!(!damping.observe("b", false, &settings, now));
+        assert!(damping.observe("b", true, &settings, now));
+    }
+
+    #[test]
+    fn test_flapping_peer_is_held_back() {
+        let mut damping = Damping::new();
+        let settings = FailureDetection {
+            successes_before_alive: 2,
+            flap_window_ms: 10_000,
+            ..Default::default()
+        };
+        let start = Instant::now();
+
+        assert!(!damping.observe("b", true, &settings, start));
+        assert!(damping.observe("b", true, &settings, start));
+        assert!(!damping.observe("b", false, &settings, start));
+
+        // Back after a second, but it only went down a second ago
+        let later = start + Duration::from_secs(1);
+        assert!(!damping.observe("b", true, &settings, later));
+        assert!(!damping.observe("b", true, &settings, later));
+        assert!(damping.is_held_back("b"));
+
+        let after_window = start + Duration::from_secs(11);
+        assert!(damping.observe("b", true, &settings, after_window));
+        assert_eq!(
+            damping.up_for("b", after_window + Duration::from_secs(5)),
+            Some(Duration::from_secs(5))
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{config::is_default_service, timestamp::Timestamp};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// How many events are kept for `status`.
+pub const MAX_EVENTS: usize = 32;
+
+/// Something an operator should hear about, whatever the verbosity.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// More than one node was running the process, e.g. after a partition healed. Everyone
+    /// but `kept` steps down.
+    SplitBrain {
+        active: Vec<String>,
+        kept: String,
+        term: u64,
+    },
+    /// `node` went silent while running the process and was fenced before we took over.
+    Fenced { node: String },
+    /// Fencing `node` failed, so we didn't take over.
+    FencingFailed { node: String, error: String },
+    /// The process exited on its own, with `code` or killed by `signal`.
+    ProcessExited {
+        pid: u32,
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// The process couldn't be started at all.
+    SpawnFailed { error: String },
+    /// The process kept failing, so we stepped down and let another node run it.
+    GaveUp { restarts: u32 },
+    /// Some of the process group survived stopping it.
+    StopFailed { error: String },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::SplitBrain { active, kept, term } => write!(
+                f,
+                "Split brain: {} were all active, keeping \"{}\" (term {})",
+                active
+                    .iter()
+                    .map(|name| format!("\"{}\"", name))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                kept,
+                term
+            ),
+            Event::Fenced { node } => write!(f, "Fenced \"{}\" before taking over", node),
+            Event::FencingFailed { node, error } => {
+                write!(f, "Couldn't fence \"{}\", not taking over: {}", node, error)
+            }
+            Event::ProcessExited { pid, code, signal } => match (code, signal) {
+                (Some(code), _) => write!(f, "Process {} exited with code {}", pid, code),
+                (None, Some(signal)) => write!(f, "Process {} killed by signal {}", pid, signal),
+                (None, None) => write!(f, "Process {} exited", pid),
+            },
+            Event::SpawnFailed { error } => write!(f, "Couldn't start the process: {}", error),
+            Event::StopFailed { error } => write!(f, "Couldn't stop the process: {}", error),
+            Event::GaveUp { restarts } => write!(
+                f,
+                "Process still failing after {} restarts, handing it over",
+                restarts
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EventRecord {
+    pub at: Timestamp,
+    /// Service the event is about, unless it's the `default` one or the whole node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    pub event: Event,
+}
+
+impl fmt::Display for EventRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.service {
+            Some(service) => write!(f, "{}: {}", service, self.event),
+            None => write!(f, "{}", self.event),
+        }
+    }
+}
+
+/// The most recent events of this node.
+#[derive(Clone, Default)]
+pub struct Events {
+    recent: Arc<Mutex<VecDeque<EventRecord>>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// Prints `event`, which happened to `service`, and keeps it around for `status`.
+    pub fn emit(&self, service: &str, event: Event) {
+        let record = EventRecord {
+            at: Timestamp::now(),
+            service: (!is_default_service(service)).then(|| service.to_string()),
+            event,
+        };
+        println!("[event] {}", record);
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == MAX_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    pub fn recent(&self) -> Vec<EventRecord> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{
+    config::{FenceAction, ProviderNode},
+    log,
+    membership::Preference,
+    node_connections::NodeConnections,
+};
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::{process::Command, time::timeout};
+
+/// Makes sure `node`, which was running `service` when it went silent, really stopped before
+/// we start it. Every action has to succeed.
+pub async fn fence(
+    actions: &[FenceAction],
+    node: &ProviderNode,
+    node_connections: &NodeConnections,
+    service: &str,
+    preference: Preference,
+) -> Result<()> {
+    for action in actions {
+        let result = match action {
+            FenceAction::Script {
+                command,
+                timeout_secs,
+            } => run_script(command, node, service, Duration::from_secs(*timeout_secs)).await,
+            FenceAction::Http {
+                url,
+                method,
+                timeout_secs,
+            } => {
+                call(
+                    url,
+                    method,
+                    node,
+                    service,
+                    Duration::from_secs(*timeout_secs),
+                )
+                .await
+            }
+            FenceAction::Acknowledgement => {
+                match node_connections.handover(node, service, preference).await {
+                    Some(true) => Ok(()),
+                    Some(false) => Err(anyhow::anyhow!("it refused to stop the process")),
+                    None => Err(anyhow::anyhow!("it didn't answer")),
+                }
+            }
+        };
+        result.with_context(|| format!("{} failed", action))?;
+        log!("-> {} succeeded for \"{}\"", action, node.name);
+    }
+    Ok(())
+}
+
+async fn run_script(
+    command: &str,
+    node: &ProviderNode,
+    service: &str,
+    limit: Duration,
+) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("P2P_FENCE_NODE", &node.name)
+        .env("P2P_FENCE_ADDRESS", &node.ip)
+        .env("P2P_FENCE_SERVICE", service)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    match timeout(limit, child.wait()).await {
+        Ok(status) => {
+            let status = status?;
+            if !status.success() {
+                bail!("exited with {}", status);
+            }
+            Ok(())
+        }
+        Err(_) => bail!("timed out after {}s", limit.as_secs()),
+    }
+}
+
+async fn call(
+    url: &str,
+    method: &str,
+    node: &ProviderNode,
+    service: &str,
+    limit: Duration,
+) -> Result<()> {
+    let url = url
+        .replace("{node}", &node.name)
+        .replace("{service}", service);
+    let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes())?;
+    let response = reqwest::Client::new()
+        .request(method, &url)
+        .timeout(limit)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("{} answered {}", url, response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::NodeRole, timestamp::Timestamp};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    fn node() -> ProviderNode {
+        ProviderNode {
+            name: "old".to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: 8080,
+            priority: 100,
+            last_updated: Timestamp::now(),
+            role: NodeRole::Provider,
+            id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_script_must_succeed() {
+        let connections = NodeConnections::new();
+        let script = |command: &str| FenceAction::Script {
+            command: command.to_string(),
+            timeout_secs: 5,
+        };
+
+        let fenced = vec![script("test \"$P2P_FENCE_NODE\" = old")];
+        assert!(fence(
+            &fenced,
+            &node(),
+            &connections,
+            "default",
+            Preference::Normal
+        )
+        .await
+        .is_ok());
+
+        let failing = vec![script("true"), script("exit 3")];
+        let error = fence(
+            &failing,
+            &node(),
+            &connections,
+            "default",
+            Preference::Normal,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("exit status: 3"));
+    }
+
+    /// A web server answering every request with `status`, or never if it's `None`. Returns
+    /// its address and the request lines it received.
+    async fn stub(status: Option<&'static str>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut silent = vec![];
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let read = stream.read(&mut buffer).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buffer[..read]);
+                let _ = requests.send(request.lines().next().unwrap_or_default().to_string());
+                match status {
+                    Some(status) => {
+                        let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    }
+                    // Keep the connection open without answering
+                    None => silent.push(stream),
+                }
+            }
+        });
+        (format!("http://{}", address), received)
+    }
+
+    fn http(url: String) -> Vec<FenceAction> {
+        vec![FenceAction::Http {
+            url,
+            method: "post".to_string(),
+            timeout_secs: 1,
+        }]
+    }
+
+    #[tokio::test]
+    async fn test_http_must_succeed() {
+        let connections = NodeConnections::new();
+
+        let (url, mut received) = stub(Some("200 OK")).await;
+        let actions = http(format!("{}/fence/{{node}}/{{service}}", url));
+        fence(
+            &actions,
+            &node(),
+            &connections,
+            "worker",
+            Preference::Normal,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            received.recv().await.unwrap(),
+            "POST /fence/old/worker HTTP/1.1"
+        );
+
+        let (url, _received) = stub(Some("503 Service Unavailable")).await;
+        let error = fence(
+            &http(url),
+            &node(),
+            &connections,
+            "default",
+            Preference::Normal,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("503"));
+
+        let (url, _received) = stub(None).await;
+        assert!(fence(
+            &http(url),
+            &node(),
+            &connections,
+            "default",
+            Preference::Normal
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acknowledgement_needs_an_answer() {
+        let connections = NodeConnections::new();
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut node = node();
+        node.port = port as u32;
+
+        let error = fence(
+            &[FenceAction::Acknowledgement],
+            &node,
+            &connections,
+            "default",
+            Preference::Normal,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("didn't answer"));
+    }
+}
@@ -41,20 +41,34 @@ pub fn start_file_watcher(config: Arc<Mutex<Config>>, config_string: Arc<Mutex<S
             debug!("event: {:?}", event);
 
             if let notify::EventKind::Modify(_) = event.kind {
-                // Refresh config
-                let config_file = get_file(&config_path);
-                let mut p = Parser::new(config_file);
-                let cfg = p.parse(Some(config_string.clone()));
-                if let Ok(cfg) = cfg {
-                    let mut config_guard = config.lock().unwrap();
-                    *config_guard = cfg;
-                    log!("Config updated: {:#?}", config_guard);
+                if let Err(e) = reload_config(&config, &config_string) {
+                    log!("Couldn't reload the config: {}", e);
                 }
             }
         }
     });
 }
 
+/// Re-reads the config file, replacing the config in use, and warns about priority ties in
+/// it.
+pub fn reload_config(
+    config: &Arc<Mutex<Config>>,
+    config_string: &Arc<Mutex<String>>,
+) -> std::io::Result<()> {
+    let config_path =
+        std::env::var("P2P_CONFIG_PATH").unwrap_or_else(|_| "p2p-failover.config.yaml".to_string());
+
+    let config_file = get_file(&config_path);
+    let mut p = Parser::new(config_file);
+    let cfg = p.parse(Some(config_string.clone()))?;
+
+    let mut config_guard = config.lock().unwrap();
+    *config_guard = cfg;
+    log!("Config updated: {:#?}", config_guard);
+    config_guard.warn_about_ties();
+    Ok(())
+}
+
 fn get_file(filename: &String) -> File {
     match File::open(filename) {
         Ok(file) => file,
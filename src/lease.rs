@@ -0,0 +1,460 @@
+use crate::{
+    config::{is_default_service, DEFAULT_SERVICE},
+    log,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a granted lease lasts. The leader renews it every heartbeat.
+pub const LEASE_DURATION: Duration = Duration::from_secs(10);
+
+/// How long a node asking for a lease waits for each vote.
+pub const LEASE_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What has to survive a restart, so we never vote twice in the same term.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+struct PersistedState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+struct Inner {
+    state: PersistedState,
+    path: Option<String>,
+    /// The lease we granted last. Outlives the term it was granted in, so a candidate bumping
+    /// the term can't take over before the lease runs out.
+    promise: Option<Promise>,
+    /// Until when we hold the lease ourselves
+    held_until: Option<Instant>,
+    /// Highest term heard of while we held the lease, adopted once we don't anymore
+    seen_term: u64,
+}
+
+struct Promise {
+    holder: String,
+    term: u64,
+    until: Instant,
+}
+
+impl Promise {
+    fn is_active(&self, now: Instant) -> bool {
+        self.until > now
+    }
+}
+
+/// Answer to a lease request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vote {
+    pub granted: bool,
+    pub term: u64,
+    /// Who holds a lease granted by us, or who we voted for in the current term
+    pub holder: Option<String>,
+}
+
+/// Election terms and leadership leases, shared between the heartbeat and the listener.
+///
+/// A node may only run the process while it holds a lease granted by its peers for the
+/// current term. Terms only go up; hearing about a higher term drops any lease we hold.
+#[derive(Clone)]
+pub struct Leases {
+    inner: Arc<Mutex<Inner>>,
+}
+
+pub fn state_path() -> String {
+    std::env::var("P2P_STATE_PATH").unwrap_or_else(|_| "p2p-failover.state.yaml".to_string())
+}
+
+/// Where the term of `service` is kept: `state_path()` for the `default` service, next to it
+/// for the others.
+pub fn state_path_for(service: &str) -> String {
+    let path = state_path();
+    if is_default_service(service) {
+        return path;
+    }
+    match path.strip_suffix(".yaml") {
+        Some(stem) => format!("{}.{}.yaml", stem, service),
+        None => format!("{}.{}", path, service),
+    }
+}
+
+/// The leases of every service, each elected on its own. Shared like `Leases`.
+#[derive(Clone)]
+pub struct ServiceLeases {
+    services: Arc<Mutex<BTreeMap<String, Leases>>>,
+    persistent: bool,
+}
+
+impl ServiceLeases {
+    /// Terms are loaded from `state_path_for` as services show up.
+    pub fn load() -> ServiceLeases {
+        ServiceLeases {
+            services: Arc::default(),
+            persistent: true,
+        }
+    }
+
+    /// Leases that aren't persisted, for tests.
+    pub fn in_memory() -> ServiceLeases {
+        ServiceLeases {
+            services: Arc::default(),
+            persistent: false,
+        }
+    }
+
+    pub fn get(&self, service: &str) -> Leases {
+        let mut services = self.services.lock().unwrap();
+        services
+            .entry(service.to_string())
+            .or_insert_with(|| {
+                if self.persistent {
+                    Leases::load(state_path_for(service))
+                } else {
+                    Leases::in_memory()
+                }
+            })
+            .clone()
+    }
+
+    /// The term of the `default` service, and those of the other services we know of.
+    pub fn terms(&self) -> (u64, BTreeMap<String, u64>) {
+        let term = self.get(DEFAULT_SERVICE).term();
+        let terms = self
+            .services
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(service, _)| !is_default_service(service))
+            .map(|(service, leases)| (service.clone(), leases.term()))
+            .collect();
+        (term, terms)
+    }
+
+    /// Takes note of the terms seen in a peer's gossip. Services we don't run aren't tracked.
+    pub fn observe(&self, term: u64, terms: &BTreeMap<String, u64>) {
+        self.get(DEFAULT_SERVICE).observe(term);
+        let services = self.services.lock().unwrap();
+        for (service, term) in terms {
+            if let Some(leases) = services.get(service) {
+                leases.observe(*term);
+            }
+        }
+    }
+}
+
+impl Leases {
+    /// Loads the term from `path`, starting from scratch if there's none yet.
+    pub fn load(path: String) -> Leases {
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => serde_yaml::from_str(&contents).unwrap_or_else(|e| {
+                log!("Ignoring unreadable state file {}: {}", path, e);
+                PersistedState::default()
+            }),
+            Err(_) => PersistedState::default(),
+        };
+
+        Leases {
+            inner: Arc::new(Mutex::new(Inner {
+                state,
+                path: Some(path),
+                promise: None,
+                held_until: None,
+                seen_term: 0,
+            })),
+        }
+    }
+
+    /// Leases that aren't persisted, for tests.
+    pub fn in_memory() -> Leases {
+        Leases {
+            inner: Arc::new(Mutex::new(Inner {
+                state: PersistedState::default(),
+                path: None,
+                promise: None,
+                held_until: None,
+                seen_term: 0,
+            })),
+        }
+    }
+
+    pub fn term(&self) -> u64 {
+        self.inner.lock().unwrap().state.term
+    }
+
+    pub fn holds_lease(&self) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .held_until
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Takes note of a term seen in a peer's message. Returns `true` if it was newer than
+    /// ours and we moved on to it, in which case any lease we held is gone.
+    ///
+    /// A lease that hasn't run out is kept: the newer term may come from an election nobody
+    /// granted, which mustn't stop a healthy active node. It steps down once a renewal is
+    /// refused instead.
+    pub fn observe(&self, term: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if term <= inner.state.term {
+            return false;
+        }
+        if inner.held_until.is_some_and(|until| until > Instant::now()) {
+            inner.seen_term = inner.seen_term.max(term);
+            return false;
+        }
+
+        if inner.held_until.take().is_some() {
+            log!("-> Term {} started elsewhere, giving up our lease", term);
+            inner.promise = None;
+        }
+        inner.adopt(term);
+        true
+    }
+
+    /// Starts a new term in which we vote for ourselves. Returns the term to ask peers for.
+    pub fn start_election(&self, local_name: &str) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state.term = inner.state.term.max(inner.seen_term) + 1;
+        inner.state.voted_for = Some(local_name.to_string());
+        inner.held_until = None;
+        inner.persist();
+        inner.state.term
+    }
+
+    /// Our peers granted `term`, asked for at `asked_at`. Returns `false` if the term moved on
+    /// in the meantime.
+    pub fn acquired(&self, term: u64, asked_at: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state.term != term {
+            return false;
+        }
+        let Some(local_name) = inner.state.voted_for.clone() else {
+            return false;
+        };
+        // Counted from before asking, so it runs out before any grant does
+        let until = asked_at + LEASE_DURATION;
+        inner.held_until = Some(until);
+        inner.promise = Some(Promise {
+            holder: local_name,
+            term,
+            until,
+        });
+        true
+    }
+
+    /// Gives up the lease, e.g. when stepping down. Our vote for ourselves goes with it, so a
+    /// peer that got the same term elsewhere (say across a partition) can keep its lease.
+    pub fn drop_lease(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.held_until.take().is_some() {
+            if let Some(promise) = inner.promise.take() {
+                inner.forget_vote(&promise.holder, promise.term);
+            }
+        }
+        if inner.seen_term > inner.state.term {
+            let term = inner.seen_term;
+            inner.adopt(term);
+        }
+    }
+
+    /// Decides on a lease request (or renewal) from `candidate` for `term`.
+    pub fn vote(&self, candidate: &str, term: u64) -> Vote {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let promised_to_other = inner
+            .promise
+            .as_ref()
+            .is_some_and(|promise| promise.holder != candidate && promise.is_active(now));
+        let renewal = inner.promise.as_ref().is_some_and(|promise| {
+            promise.holder == candidate && promise.term == term && promise.is_active(now)
+        });
+
+        let granted = if renewal {
+            // Even if a failed election moved our term on since
+            true
+        } else if term < inner.state.term {
+            false
+        } else if promised_to_other {
+            // Someone else's lease hasn't run out yet, whatever term they hold it in
+            false
+        } else if term > inner.state.term {
+            inner.state = PersistedState {
+                term,
+                voted_for: Some(candidate.to_string()),
+            };
+            inner.held_until = None;
+            inner.persist();
+            true
+        } else {
+            match &inner.state.voted_for {
+                None => {
+                    inner.state.voted_for = Some(candidate.to_string());
+                    inner.persist();
+                    true
+                }
+                Some(voted_for) => voted_for == candidate,
+            }
+        };
+
+        if granted {
+            inner.promise = Some(Promise {
+                holder: candidate.to_string(),
+                term,
+                until: now + LEASE_DURATION,
+            });
+        }
+        Vote {
+            granted,
+            term: inner.state.term,
+            holder: match &inner.promise {
+                Some(promise) if promise.is_active(now) => Some(promise.holder.clone()),
+                _ => inner.state.voted_for.clone(),
+            },
+        }
+    }
+
+    /// `holder` stepped down; others may ask for the lease, even in the term it had.
+    pub fn release(&self, holder: &str, term: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .promise
+            .as_ref()
+            .is_some_and(|promise| promise.holder == holder && promise.term <= term)
+        {
+            inner.promise = None;
+        }
+        inner.forget_vote(holder, term);
+    }
+}
+
+impl Inner {
+    fn adopt(&mut self, term: u64) {
+        self.state = PersistedState {
+            term,
+            voted_for: None,
+        };
+        self.persist();
+    }
+
+    /// Lets a stepped down node's term be granted to someone else.
+    fn forget_vote(&mut self, holder: &str, term: u64) {
+        if self.state.term == term && self.state.voted_for.as_deref() == Some(holder) {
+            self.state.voted_for = None;
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = serde_yaml::to_string(&self.state)
+            .map_err(|e| e.to_string())
+            .and_then(|yaml| fs::write(path, yaml).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log!("Couldn't persist the election term to {}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_lease_per_term() {
+        let voter = Leases::in_memory();
+        assert!(voter.vote("a", 1).granted);
+        assert!(voter.vote("a", 1).granted, "renewals are granted");
+
+        let vote = voter.vote("b", 2);
+        assert!(!vote.granted, "a's lease hasn't run out");
+        assert_eq!(vote.holder.as_deref(), Some("a"));
+
+        voter.release("a", 1);
+        assert!(voter.vote("b", 1).granted, "a gave term 1 up");
+        assert!(!voter.vote("c", 1).granted, "already voted for b in term 1");
+        assert!(voter.vote("b", 2).granted);
+        assert!(!voter.vote("a", 1).granted, "stale term");
+    }
+
+    #[test]
+    fn test_higher_term_drops_an_expired_lease() {
+        let leases = Leases::in_memory();
+        let term = leases.start_election("a");
+        assert!(leases.acquired(term, Instant::now() - LEASE_DURATION));
+
+        assert!(!leases.observe(term));
+        assert!(leases.observe(term + 1));
+        assert!(!leases.holds_lease());
+        assert_eq!(leases.term(), term + 1);
+    }
+
+    #[test]
+    fn test_failed_election_keeps_the_lease() {
+        let active = Leases::in_memory();
+        let voter = Leases::in_memory();
+        let term = active.start_election("a");
+        assert!(voter.vote("a", term).granted);
+        assert!(active.acquired(term, Instant::now()));
+
+        // b briefly misses a probe and asks for the next term, which isn't granted
+        assert!(!voter.vote("b", term + 1).granted);
+        // Its gossip spreads the term anyway
+        voter.observe(term + 1);
+        assert!(!active.observe(term + 1));
+        assert!(active.holds_lease());
+
+        let renewal = voter.vote("a", term);
+        assert!(renewal.granted);
+        assert!(!active.observe(renewal.term));
+        assert!(active.acquired(term, Instant::now()));
+
+        // Stepping down catches up with the newer term
+        active.drop_lease();
+        assert_eq!(active.term(), term + 1);
+        assert!(active.start_election("a") > term + 1);
+    }
+
+    #[test]
+    fn test_stepping_down_frees_the_term() {
+        // a and b both got term 1, each on its side of a partition
+        let a = Leases::in_memory();
+        let term = a.start_election("a");
+        assert!(a.acquired(term, Instant::now()));
+        assert!(!a.vote("b", term).granted);
+
+        a.drop_lease();
+        assert!(a.vote("b", term).granted);
+    }
+
+    #[test]
+    fn test_promise_outlives_the_term() {
+        let voter = Leases::in_memory();
+        assert!(voter.vote("a", 1).granted);
+
+        // A candidate's gossip moves the term on, a's lease still has to run out
+        voter.observe(2);
+        assert!(!voter.vote("b", 2).granted);
+        voter.release("a", 1);
+        assert!(voter.vote("b", 2).granted);
+    }
+
+    #[test]
+    fn test_term_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.yaml").to_string_lossy().to_string();
+
+        let leases = Leases::load(path.clone());
+        leases.vote("b", 7);
+        let restarted = Leases::load(path);
+        assert_eq!(restarted.term(), 7);
+        assert!(!restarted.vote("c", 7).granted);
+    }
+}
@@ -1,11 +1,24 @@
+pub mod auth;
 pub mod config;
+pub mod control;
+pub mod damping;
 pub mod debug;
+pub mod event;
+pub mod fencing;
 pub mod file_watcher;
+pub mod lease;
 pub mod log;
+pub mod membership;
 pub mod node;
 pub mod node_connections;
+pub mod output;
 pub mod parser;
 pub mod pending_verification;
+pub mod phi;
 pub mod process;
+pub mod protocol;
+pub mod resolver;
+pub mod supervisor;
 pub mod tcp_listener;
 pub mod timestamp;
+pub mod tls;
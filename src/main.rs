@@ -1,13 +1,124 @@
 use anyhow::Result;
-use p2p_failover::{file_watcher, node::Node, parser::Parser, tcp_listener};
+use p2p_failover::{
+    auth::ClusterAuth,
+    config::NodeRole,
+    control::{self, ControlRequest, ControlResponse, Status},
+    file_watcher, log,
+    node::Node,
+    parser::Parser,
+    process, tcp_listener,
+    tls::TlsContext,
+};
 use std::{
     fs::File,
     sync::{Arc, Mutex},
-    thread, time,
 };
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+const USAGE: &str =
+    "Usage: p2p-failover [status [--json] | reload | drain | promote | demote | resume]
+
+Without a command, runs the node. Commands talk to a running node through its control
+socket (P2P_CONTROL_SOCKET, default p2p-failover.sock).";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = args.first() else {
+        return run();
+    };
+
+    let request = match command.as_str() {
+        "status" => ControlRequest::Status,
+        "reload" => ControlRequest::Reload,
+        "drain" => ControlRequest::Drain,
+        "promote" => ControlRequest::Promote,
+        "demote" => ControlRequest::Demote,
+        "resume" => ControlRequest::Resume,
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        other => {
+            eprintln!("Unknown command \"{}\"\n\n{}", other, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let json = args.iter().any(|arg| arg == "--json");
+
+    match control::send(&control::socket_path(), request)? {
+        response if json => println!("{}", serde_json::to_string_pretty(&response)?),
+        ControlResponse::Status(status) => print_status(&status),
+        ControlResponse::Done { message } => println!("{}", message),
+        ControlResponse::Error { message } => {
+            eprintln!("Error: {}", message);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn print_status(status: &Status) {
+    println!(
+        "{}: {}{:?}{}",
+        status.node,
+        if status.node_role == NodeRole::Witness {
+            "Witness, "
+        } else {
+            ""
+        },
+        status.preference,
+        match status.quorum {
+            Some(true) => ", quorum",
+            Some(false) => ", no quorum",
+            None => "",
+        },
+    );
+    for service in &status.services {
+        println!(
+            "  service \"{}\": {:?}, term {}{}",
+            service.name,
+            service.role,
+            service.term,
+            match (service.pid, &service.active) {
+                (Some(pid), _) => format!(", process {}", pid),
+                (None, Some(active)) if *active != status.node => format!(", on \"{}\"", active),
+                _ => String::new(),
+            }
+        );
+    }
+    for peer in &status.peers {
+        println!(
+            "  {:<16} {:<24} {:<14} {:<6} {:<8} {}{}",
+            peer.name,
+            peer.address,
+            match peer.role {
+                NodeRole::Witness => "witness".to_string(),
+                NodeRole::Provider => format!("priority {}", peer.priority),
+            },
+            if peer.alive { "alive" } else { "down" },
+            peer.state
+                .map(|state| format!("{:?}", state))
+                .unwrap_or_else(|| "-".to_string()),
+            peer.phi
+                .map(|phi| format!("phi {:.1}", phi))
+                .unwrap_or_default(),
+            if peer.preference == Default::default() {
+                String::new()
+            } else {
+                format!(" ({:?})", peer.preference)
+            }
+        );
+    }
+    for record in &status.events {
+        println!("  {}  {}", record.at, record);
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn run() -> Result<()> {
     let config_path =
         std::env::var("P2P_CONFIG_PATH").unwrap_or_else(|_| "p2p-failover.config.yaml".to_string());
 
@@ -17,17 +128,82 @@ async fn main() -> Result<()> {
     let config_string = Arc::new(Mutex::new(String::new()));
     let config = {
         let cfg = p.parse(Some(config_string.clone()))?;
+        cfg.warn_about_ties();
         Arc::new(Mutex::new(cfg))
     };
 
+    process::become_subreaper();
+    let tls = TlsContext::from_config(&config.lock().unwrap())?;
+    let auth = ClusterAuth::from_config(&config.lock().unwrap());
+
     let mut node = Node::new(config.clone());
+    node.node_connections
+        .set_tls(tls.as_ref().map(|tls| tls.client.clone()));
+    node.node_connections.set_auth(auth);
+
+    let socket_path = control::socket_path();
+    let mut commands = control::start_control_socket(socket_path.clone())?;
 
     file_watcher::start_file_watcher(config.clone(), config_string.clone());
-    tcp_listener::start_tcp_listener(config.clone(), config_string.clone());
+    let (handover_tx, mut handovers) = mpsc::channel(4);
+    tcp_listener::start_tcp_listener(tls.map(|tls| tls.server), &node, handover_tx);
+    let wake = node.wake.clone();
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
         node.heartbeat().await;
-        thread::sleep(time::Duration::from_secs(1))
+
+        let interval = config
+            .lock()
+            .unwrap()
+            .failure_detection
+            .heartbeat_interval();
+        let tick = tokio::time::sleep(interval);
+        tokio::pin!(tick);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => {
+                    log!("Leaving the cluster");
+                    node.leave().await;
+                    let _ = std::fs::remove_file(&socket_path);
+                    return Ok(());
+                }
+                Some(command) = commands.recv() => {
+                    let response = match command.request {
+                        ControlRequest::Reload => {
+                            match file_watcher::reload_config(&config, &config_string) {
+                                Ok(()) => ControlResponse::Done {
+                                    message: "Config reloaded".to_string(),
+                                },
+                                Err(e) => ControlResponse::Error {
+                                    message: format!("Couldn't reload the config: {}", e),
+                                },
+                            }
+                        }
+                        request => node.control(request).await,
+                    };
+                    let _ = command.reply.send(response);
+                }
+                Some(handover) = handovers.recv() => {
+                    let stopped = node
+                        .hand_over(&handover.from, &handover.service, handover.preference)
+                        .await;
+                    let _ = handover.reply.send(stopped);
+                }
+                _ = wake.notified() => break,
+                _ = &mut tick => break,
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
     }
 }
 
@@ -0,0 +1,601 @@
+use crate::config::{is_default_service, Config, ProviderNode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Members probed per heartbeat. Everyone else is learned about through gossip.
+pub const PROBE_FANOUT: usize = 1;
+/// How long a suspected member has to refute the suspicion before it's declared dead.
+pub const SUSPICION_TIMEOUT: Duration = Duration::from_secs(3);
+/// Maximum number of updates piggybacked on a single message.
+pub const MAX_PIGGYBACK: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Alive,
+    /// Missed a probe; still counts as alive until `SUSPICION_TIMEOUT` passes
+    Suspect,
+    Dead,
+    /// Shut down gracefully
+    Left,
+}
+
+/// An operator's say in elections, set through the control socket and gossiped with the
+/// member's state.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Preference {
+    #[default]
+    Normal,
+    /// Outranks every other node, regardless of priority
+    Promoted,
+    /// Ranks below every other node, only runs the process if nobody else can
+    Demoted,
+    /// Never runs the process
+    Drained,
+}
+
+/// Which services a member runs, and which ones it gave up on.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Activity {
+    /// The term the member runs the `default` service in, `None` while it stands by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_term: Option<u64>,
+    /// Terms the member runs the other services in
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub active_terms: BTreeMap<String, u64>,
+    /// Services that kept failing on the member. It only runs them if nobody else can.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub gave_up: BTreeSet<String>,
+}
+
+impl Activity {
+    pub fn term(&self, service: &str) -> Option<u64> {
+        if is_default_service(service) {
+            self.active_term
+        } else {
+            self.active_terms.get(service).copied()
+        }
+    }
+
+    /// Returns whether the term changed.
+    fn set_term(&mut self, service: &str, term: Option<u64>) -> bool {
+        if self.term(service) == term {
+            return false;
+        }
+        match (is_default_service(service), term) {
+            (true, term) => self.active_term = term,
+            (false, Some(term)) => {
+                self.active_terms.insert(service.to_string(), term);
+            }
+            (false, None) => {
+                self.active_terms.remove(service);
+            }
+        }
+        true
+    }
+
+    /// `preference` as it applies to `service`.
+    pub fn preference_for(&self, preference: Preference, service: &str) -> Preference {
+        if preference != Preference::Drained && self.gave_up.contains(service) {
+            Preference::Demoted
+        } else {
+            preference
+        }
+    }
+}
+
+/// A membership event as disseminated between nodes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MemberUpdate {
+    pub node: ProviderNode,
+    pub state: MemberState,
+    /// Only ever raised by the node itself, to refute suspicions or announce a restart
+    pub incarnation: u64,
+    #[serde(default)]
+    pub preference: Preference,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub node: ProviderNode,
+    pub state: MemberState,
+    pub incarnation: u64,
+    pub preference: Preference,
+    pub activity: Activity,
+    pub since: Instant,
+    /// Consecutive probes of ours the member didn't answer, directly or indirectly
+    pub failed_probes: u32,
+}
+
+impl Member {
+    /// The term the member runs `service` in, `None` while it stands by
+    pub fn active_term(&self, service: &str) -> Option<u64> {
+        self.activity.term(service)
+    }
+
+    pub fn preference_for(&self, service: &str) -> Preference {
+        self.activity.preference_for(self.preference, service)
+    }
+}
+
+struct Broadcast {
+    update: MemberUpdate,
+    transmissions_left: u32,
+}
+
+struct Inner {
+    local: ProviderNode,
+    incarnation: u64,
+    preference: Preference,
+    activity: Activity,
+    members: Vec<Member>,
+    broadcasts: Vec<Broadcast>,
+    next_probe: usize,
+}
+
+/// This node's view of the cluster, kept in sync with peers by gossip (SWIM style).
+#[derive(Clone)]
+pub struct Membership {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Membership {
+    pub fn new(config: &Config) -> Membership {
+        let local = config
+            .nodes
+            .iter()
+            .find(|node| node.name == config.config_metadata.name)
+            .cloned()
+            .expect("Local node missing from config");
+
+        // Starting from the clock means a restarted node always outranks what peers remember
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let membership = Membership {
+            inner: Arc::new(Mutex::new(Inner {
+                local,
+                incarnation,
+                preference: Preference::Normal,
+                activity: Activity::default(),
+                members: vec![],
+                broadcasts: vec![],
+                next_probe: 0,
+            })),
+        };
+        membership.sync_config(config);
+        membership
+    }
+
+    /// Picks up nodes added to the config (file reloads, admitted peers). They start out dead
+    /// until we hear from them.
+    pub fn sync_config(&self, config: &Config) {
+        let mut inner = self.inner.lock().unwrap();
+        for node in &config.nodes {
+            if node.name == inner.local.name {
+                continue;
+            }
+            match inner.members.iter_mut().find(|m| m.node.name == node.name) {
+                Some(member) => member.node = node.clone(),
+                None => inner.members.push(Member {
+                    node: node.clone(),
+                    state: MemberState::Dead,
+                    incarnation: 0,
+                    preference: Preference::Normal,
+                    activity: Activity::default(),
+                    since: Instant::now(),
+                    failed_probes: 0,
+                }),
+            }
+        }
+    }
+
+    pub fn members(&self) -> Vec<Member> {
+        self.inner.lock().unwrap().members.clone()
+    }
+
+    /// Alive and suspected members both count as up.
+    pub fn is_alive(&self, name: &str) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .members
+            .iter()
+            .find(|m| m.node.name == name)
+            .is_some_and(|m| matches!(m.state, MemberState::Alive | MemberState::Suspect))
+    }
+
+    pub fn preference(&self) -> Preference {
+        self.inner.lock().unwrap().preference
+    }
+
+    /// Our preference for `service`: demoted if it kept failing here.
+    pub fn preference_for(&self, service: &str) -> Preference {
+        let inner = self.inner.lock().unwrap();
+        inner.activity.preference_for(inner.preference, service)
+    }
+
+    /// Changes our own preference, which also gives services we gave up on another chance.
+    /// The incarnation is raised so peers take the news over what they already know about us.
+    pub fn set_preference(&self, preference: Preference) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.preference != preference || !inner.activity.gave_up.is_empty() {
+            inner.preference = preference;
+            inner.activity.gave_up.clear();
+            inner.incarnation += 1;
+        }
+    }
+
+    /// Announces that `service` kept failing here, so others should run it if they can.
+    pub fn give_up(&self, service: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.activity.gave_up.insert(service.to_string()) {
+            inner.incarnation += 1;
+        }
+    }
+
+    pub fn active_term(&self, service: &str) -> Option<u64> {
+        self.inner.lock().unwrap().activity.term(service)
+    }
+
+    /// Announces that we started (`Some(term)`) or stopped running `service`.
+    pub fn set_active_term(&self, service: &str, active_term: Option<u64>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.activity.set_term(service, active_term) {
+            inner.incarnation += 1;
+        }
+    }
+
+    /// `name` told us it stopped running `service`, ahead of its gossip. Returns whether we
+    /// thought it was active.
+    pub fn record_stepped_down(&self, name: &str, service: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.members.iter_mut().find(|m| m.node.name == name) {
+            Some(member) => member.activity.set_term(service, None),
+            None => false,
+        }
+    }
+
+    pub fn alive_names(&self) -> Vec<String> {
+        self.members()
+            .into_iter()
+            .filter(|m| m.state == MemberState::Alive)
+            .map(|m| m.node.name)
+            .collect()
+    }
+
+    /// The next `n` members to probe, round robin. Dead members are included so partitions
+    /// heal; members that left are not.
+    pub fn next_probe_targets(&self, n: usize) -> Vec<ProviderNode> {
+        let mut inner = self.inner.lock().unwrap();
+        let candidates: Vec<ProviderNode> = inner
+            .members
+            .iter()
+            .filter(|m| m.state != MemberState::Left)
+            .map(|m| m.node.clone())
+            .collect();
+        if candidates.is_empty() {
+            return vec![];
+        }
+
+        let start = inner.next_probe % candidates.len();
+        inner.next_probe = start + n.min(candidates.len());
+        candidates
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(n.min(candidates.len()))
+            .cloned()
+            .collect()
+    }
+
+    /// Updates to piggyback on the next message: our own state first, then recent events.
+    pub fn piggyback(&self) -> Vec<MemberUpdate> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut updates = vec![MemberUpdate {
+            node: inner.local.clone(),
+            state: MemberState::Alive,
+            incarnation: inner.incarnation,
+            preference: inner.preference,
+            activity: inner.activity.clone(),
+        }];
+
+        for broadcast in inner.broadcasts.iter_mut().take(MAX_PIGGYBACK - 1) {
+            broadcast.transmissions_left -= 1;
+            updates.push(broadcast.update.clone());
+        }
+        inner.broadcasts.retain(|b| b.transmissions_left > 0);
+        updates
+    }
+
+    /// Merges gossip from a peer. Returns nodes we didn't know about before.
+    pub fn apply(&self, updates: Vec<MemberUpdate>) -> Vec<ProviderNode> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut joined = vec![];
+
+        for update in updates {
+            if update.node.name == inner.local.name {
+                // Refute anything claiming we're gone
+                if update.state != MemberState::Alive && update.incarnation >= inner.incarnation {
+                    inner.incarnation = update.incarnation + 1;
+                }
+                continue;
+            }
+
+            match inner
+                .members
+                .iter()
+                .position(|m| m.node.name == update.node.name)
+            {
+                Some(index) => {
+                    let member = &mut inner.members[index];
+                    if !overrides(&update, member) {
+                        continue;
+                    }
+                    member.state = update.state;
+                    member.incarnation = update.incarnation;
+                    member.preference = update.preference;
+                    member.activity = update.activity.clone();
+                    member.node = update.node.clone();
+                    member.since = Instant::now();
+                    inner.enqueue(update);
+                }
+                None if matches!(update.state, MemberState::Alive | MemberState::Suspect) => {
+                    inner.members.push(Member {
+                        node: update.node.clone(),
+                        state: update.state,
+                        incarnation: update.incarnation,
+                        preference: update.preference,
+                        activity: update.activity.clone(),
+                        since: Instant::now(),
+                        failed_probes: 0,
+                    });
+                    joined.push(update.node.clone());
+                    inner.enqueue(update);
+                }
+                None => (),
+            }
+        }
+
+        joined
+    }
+
+    /// We got an answer from `name` ourselves.
+    pub fn record_ack(&self, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(member) = inner.members.iter_mut().find(|m| m.node.name == name) {
+            member.failed_probes = 0;
+            if member.state != MemberState::Alive {
+                member.state = MemberState::Alive;
+                member.since = Instant::now();
+            }
+        }
+    }
+
+    /// Neither we nor any helper could reach `name`. Returns how many probes in a row it
+    /// missed.
+    pub fn record_failure(&self, name: &str) -> u32 {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.members.iter_mut().find(|m| m.node.name == name) {
+            Some(member) => {
+                member.failed_probes = member.failed_probes.saturating_add(1);
+                member.failed_probes
+            }
+            None => 0,
+        }
+    }
+
+    /// Starts suspecting `name`, which becomes dead unless it refutes in time.
+    pub fn suspect(&self, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(member) = inner.members.iter_mut().find(|m| m.node.name == name) else {
+            return;
+        };
+        if member.state != MemberState::Alive {
+            return;
+        }
+
+        member.state = MemberState::Suspect;
+        member.since = Instant::now();
+        let update = MemberUpdate {
+            node: member.node.clone(),
+            state: MemberState::Suspect,
+            incarnation: member.incarnation,
+            preference: member.preference,
+            activity: member.activity.clone(),
+        };
+        inner.enqueue(update);
+    }
+
+    /// Declares members dead whose suspicion timed out. Returns their names.
+    pub fn expire_suspects(&self) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut expired = vec![];
+        for member in inner.members.iter_mut() {
+            if member.state == MemberState::Suspect && member.since.elapsed() > SUSPICION_TIMEOUT {
+                member.state = MemberState::Dead;
+                member.since = Instant::now();
+                expired.push(MemberUpdate {
+                    node: member.node.clone(),
+                    state: MemberState::Dead,
+                    incarnation: member.incarnation,
+                    preference: member.preference,
+                    activity: member.activity.clone(),
+                });
+            }
+        }
+
+        let names = expired.iter().map(|u| u.node.name.clone()).collect();
+        for update in expired {
+            inner.enqueue(update);
+        }
+        names
+    }
+
+    /// The update announcing that we're leaving the cluster.
+    pub fn leave(&self) -> MemberUpdate {
+        let inner = self.inner.lock().unwrap();
+        MemberUpdate {
+            node: inner.local.clone(),
+            state: MemberState::Left,
+            incarnation: inner.incarnation,
+            preference: inner.preference,
+            activity: Activity::default(),
+        }
+    }
+}
+
+impl Inner {
+    fn enqueue(&mut self, update: MemberUpdate) {
+        // Enough retransmissions to reach everyone with high probability
+        let transmissions = 3 * (usize::BITS - (self.members.len() + 1).leading_zeros());
+        self.broadcasts
+            .retain(|b| b.update.node.name != update.node.name);
+        self.broadcasts.push(Broadcast {
+            update,
+            transmissions_left: transmissions,
+        });
+    }
+}
+
+/// SWIM precedence: higher incarnations win; at the same incarnation, suspect beats alive and
+/// dead/left beat both.
+fn overrides(update: &MemberUpdate, member: &Member) -> bool {
+    let rank = |state: MemberState| match state {
+        MemberState::Alive => 0,
+        MemberState::Suspect => 1,
+        MemberState::Dead | MemberState::Left => 2,
+    };
+
+    if update.incarnation != member.incarnation {
+        return update.incarnation > member.incarnation;
+    }
+    rank(update.state) > rank(member.state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn membership(local: &str) -> Membership {
+        let yaml = format!(
+            r#"
+nodes:
+- name: a
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: b
+  ip: 127.0.0.1
+  port: 8081
+  priority: 50
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: {local}
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+"#
+        );
+        Membership::new(&Parser::new(yaml.as_bytes()).parse(None).unwrap())
+    }
+
+    fn update(from: &Membership, name: &str, state: MemberState, incarnation: u64) -> MemberUpdate {
+        let node = from
+            .members()
+            .into_iter()
+            .find(|m| m.node.name == name)
+            .unwrap()
+            .node;
+        MemberUpdate {
+            node,
+            state,
+            incarnation,
+            preference: Preference::Normal,
+            activity: Activity::default(),
+        }
+    }
+
+    #[test]
+    fn test_gossip_spreads_new_members() {
+        let a = membership("a");
+        let b = membership("b");
+
+        // b tells a about itself and about a node a has never heard of
+        let mut c = update(&a, "b", MemberState::Alive, 1);
+        c.node.name = "c".to_string();
+        let mut updates = b.piggyback();
+        updates.push(c);
+
+        let joined = a.apply(updates);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].name, "c");
+        assert!(a.is_alive("b"));
+        assert!(a.is_alive("c"));
+    }
+
+    #[test]
+    fn test_suspect_expires_and_can_be_refuted() {
+        let a = membership("a");
+        a.apply(vec![update(&a, "b", MemberState::Alive, 5)]);
+
+        a.suspect("b");
+        assert!(a.is_alive("b"));
+
+        // Same incarnation can't clear the suspicion, a higher one can
+        a.apply(vec![update(&a, "b", MemberState::Alive, 5)]);
+        assert_eq!(a.members()[0].state, MemberState::Suspect);
+        a.apply(vec![update(&a, "b", MemberState::Alive, 6)]);
+        assert_eq!(a.members()[0].state, MemberState::Alive);
+
+        a.suspect("b");
+        a.inner.lock().unwrap().members[0].since -= SUSPICION_TIMEOUT * 2;
+        assert_eq!(a.expire_suspects(), vec!["b".to_string()]);
+        assert!(!a.is_alive("b"));
+    }
+
+    #[test]
+    fn test_refutes_own_suspicion() {
+        let a = membership("a");
+        let b = membership("b");
+        let incarnation = a.inner.lock().unwrap().incarnation;
+
+        a.apply(vec![update(&b, "a", MemberState::Suspect, incarnation)]);
+        assert_eq!(a.piggyback()[0].incarnation, incarnation + 1);
+    }
+
+    #[test]
+    fn test_preference_is_gossiped() {
+        let a = membership("a");
+        let b = membership("b");
+
+        a.apply(b.piggyback());
+        b.set_preference(Preference::Drained);
+        a.apply(b.piggyback());
+        assert_eq!(a.members()[0].preference, Preference::Drained);
+
+        b.set_active_term("default", Some(3));
+        b.set_active_term("worker", Some(7));
+        a.apply(b.piggyback());
+        assert_eq!(a.members()[0].active_term("default"), Some(3));
+        assert_eq!(a.members()[0].active_term("worker"), Some(7));
+
+        b.set_preference(Preference::Normal);
+        b.give_up("worker");
+        a.apply(b.piggyback());
+        assert_eq!(a.members()[0].preference_for("default"), Preference::Normal);
+        assert_eq!(a.members()[0].preference_for("worker"), Preference::Demoted);
+    }
+}
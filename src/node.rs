@@ -1,149 +1,1749 @@
-use crate::{config::Config, log, node_connections::NodeConnections, process::Process};
-use futures::future::join_all;
-use std::sync::{Arc, Mutex};
-use tokio::task;
+use crate::{
+    config::{
+        Config, FailureDetection, Preemption, PreemptionMode, ProviderNode, Service, TieBreaker,
+    },
+    control::{ControlRequest, ControlResponse, PeerStatus, Role, ServiceStatus, Status},
+    damping::Damping,
+    event::{Event, Events},
+    fencing,
+    lease::{Leases, ServiceLeases},
+    log,
+    membership::{Member, Membership, Preference, PROBE_FANOUT},
+    node_connections::NodeConnections,
+    pending_verification::{self, PendingVerifications},
+    phi::PhiAccrual,
+    process,
+    supervisor::{Health, Supervisor},
+};
+use chrono::Utc;
+use futures::future::{join_all, select, Either};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, Notify};
 
+/// How many reachable peers are asked to ping a host we couldn't reach ourselves.
+pub const INDIRECT_PROBES: usize = 3;
+/// How long the active node gets to stop the process when another node takes over.
+pub const HANDOVER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A peer asking us to stop `service` so it can take over. `reply` says whether we did.
+pub struct Handover {
+    pub from: String,
+    pub service: String,
+    pub preference: Preference,
+    pub reply: oneshot::Sender<bool>,
+}
+
+/// A handover we asked `active` for. It's awaited in the background, so neither the heartbeat
+/// nor the handovers others ask us for wait on it.
 #[derive(Clone)]
-pub struct Node {
+struct PendingHandover {
+    active: String,
+    /// Set once `active` answered, to `None` if it didn't in time
+    answer: Arc<OnceLock<Option<bool>>>,
+}
+
+/// Where we stand with one service. Each one is elected on its own.
+#[derive(Clone)]
+struct ServiceState {
+    /// Its latest config, kept once it's removed from the config file until we stopped it
+    config: Service,
+    /// Whether we run it
     alive: bool,
+    supervisor: Option<Arc<Mutex<Supervisor>>>,
+    leases: Leases,
+    /// Other active nodes we keep running against while they step down
+    split_brain: Vec<String>,
+    handover: Option<PendingHandover>,
+}
+
+impl ServiceState {
+    fn new(config: Service, leases: Leases) -> ServiceState {
+        ServiceState {
+            config,
+            alive: false,
+            supervisor: None,
+            leases,
+            split_brain: vec![],
+            handover: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Node {
     pub config: Arc<Mutex<Config>>,
-    alives: Vec<bool>,
-    process: Option<Arc<Mutex<Process>>>,
+    /// Whether each peer counts as up, by name. Nodes added to the config since the last
+    /// heartbeat aren't in it yet and count as down.
+    alives: HashMap<String, bool>,
+    damping: Damping,
+    services: Vec<ServiceState>,
     pub node_connections: NodeConnections,
+    pub pending_verifications: PendingVerifications,
+    pub membership: Membership,
+    pub leases: ServiceLeases,
+    pub phi: PhiAccrual,
+    pub events: Events,
+    /// Notified when a heartbeat should run right away, e.g. because the active node stepped
+    /// down
+    pub wake: Arc<Notify>,
+    /// When our leases were last renewed
+    renewed_at: Arc<Mutex<Instant>>,
+    bootstrapped: bool,
 }
 
 impl Node {
     pub fn new(config: Arc<Mutex<Config>>) -> Node {
-        let alives = vec![false; config.lock().unwrap().nodes.len()];
+        Node::with_leases(config, ServiceLeases::load())
+    }
+
+    fn with_leases(config: Arc<Mutex<Config>>, leases: ServiceLeases) -> Node {
+        let (alives, local_name, membership, services) = {
+            let config = config.lock().unwrap();
+            (
+                HashMap::new(),
+                config.config_metadata.name.clone(),
+                Membership::new(&config),
+                config
+                    .services()
+                    .into_iter()
+                    .map(|service| {
+                        let service_leases = leases.get(&service.name);
+                        ServiceState::new(service, service_leases)
+                    })
+                    .collect(),
+            )
+        };
+
+        let mut node_connections = NodeConnections::new();
+        node_connections.set_local_name(local_name);
 
         Node {
-            alive: false,
             config,
             alives,
-            process: None,
-            node_connections: NodeConnections::new(),
+            damping: Damping::new(),
+            services,
+            node_connections,
+            pending_verifications: PendingVerifications::new(),
+            membership,
+            leases,
+            phi: PhiAccrual::new(),
+            events: Events::new(),
+            wake: Arc::new(Notify::new()),
+            renewed_at: Arc::new(Mutex::new(Instant::now())),
+            bootstrapped: false,
         }
     }
 
-    /// Returns the amount of alive hosts
+    fn service(&self, name: &str) -> &ServiceState {
+        self.services
+            .iter()
+            .find(|s| s.config.name == name)
+            .expect("Unknown service")
+    }
+
+    fn service_mut(&mut self, name: &str) -> &mut ServiceState {
+        self.services
+            .iter_mut()
+            .find(|s| s.config.name == name)
+            .expect("Unknown service")
+    }
+
+    fn service_names(&self) -> Vec<String> {
+        self.services
+            .iter()
+            .map(|s| s.config.name.clone())
+            .collect()
+    }
+
+    /// Picks up services added to, changed in or removed from the config. Removed ones are
+    /// stopped.
+    async fn sync_services(&mut self) {
+        let services = self.config.lock().unwrap().services();
+        let configured: Vec<String> = services.iter().map(|s| s.name.clone()).collect();
+        for service in services {
+            match self
+                .services
+                .iter_mut()
+                .find(|s| s.config.name == service.name)
+            {
+                Some(state) => state.config = service,
+                None => {
+                    log!("-> New service \"{}\"", service.name);
+                    let leases = self.leases.get(&service.name);
+                    self.services.push(ServiceState::new(service, leases));
+                }
+            }
+        }
+
+        for name in self.service_names() {
+            if configured.contains(&name) {
+                continue;
+            }
+            log!("-> Service \"{}\" was removed from the config", name);
+            if self.service(&name).alive && !self.step_down(&name).await {
+                // Still running, we'll try again next heartbeat
+                continue;
+            }
+            self.services.retain(|s| s.config.name != name);
+        }
+    }
+
+    /// Probes a few members, merges their gossip and returns the amount of alive hosts
     pub async fn check_hosts(&mut self) -> u8 {
-        let config_metadata_name = self.config.lock().unwrap().config_metadata.name.clone();
-        let alives = Arc::new(Mutex::new(0u8));
-        let mut handles = Vec::new();
+        let (config_version, settings) = {
+            let config = self.config.lock().unwrap();
+            self.membership.sync_config(&config);
+            (
+                config.config_metadata.last_updated.clone(),
+                config.failure_detection.clone(),
+            )
+        };
+        self.node_connections
+            .set_probe_timeout(settings.probe_timeout());
+
+        // Everyone once at startup, then only a few members per heartbeat, plus those we
+        // haven't heard from for suspiciously long
+        let targets = if self.bootstrapped {
+            let mut targets = self.membership.next_probe_targets(PROBE_FANOUT);
+            for member in self.overdue(&settings) {
+                if !targets.iter().any(|host| host.name == member.name) {
+                    targets.push(member);
+                }
+            }
+            targets
+        } else {
+            self.bootstrapped = true;
+            self.membership
+                .members()
+                .into_iter()
+                .map(|member| member.node)
+                .collect()
+        };
+
+        let (term, terms) = self.leases.terms();
+        let probes = targets.into_iter().map(|host| {
+            let updates = self.membership.piggyback();
+            let config_version = config_version.clone();
+            let terms = terms.clone();
+            let node_connections = &self.node_connections;
 
-        for (index, host) in self.config.lock().unwrap().nodes.iter().enumerate() {
-            if host.name == config_metadata_name {
+            async move {
+                log!("Checking: {}:{}", &host.ip, &host.port);
+                let reply = node_connections
+                    .gossip(&host, updates, config_version, term, terms)
+                    .await;
+                (host, reply)
+            }
+        });
+
+        let mut unreachable = Vec::new();
+        for (host, reply) in join_all(probes).await {
+            let Some(reply) = reply else {
+                unreachable.push(host);
                 continue;
+            };
+
+            self.membership.record_ack(&host.name);
+            self.phi.heartbeat(&host.name);
+            self.leases.observe(reply.term, &reply.terms);
+            let joined = self.membership.apply(reply.updates);
+            pending_verification::verify_joined(
+                &self.pending_verifications,
+                &self.node_connections,
+                &self.config,
+                joined,
+            );
+
+            if reply.config_version > config_version {
+                log!("-> \"{}\" has a newer config, pulling it", host.name);
+                self.node_connections
+                    .update_config_from(&host.name, self.config.clone())
+                    .await;
             }
+        }
 
-            self.alives[index] = false;
-            let host_clone = host.clone();
-            let alives_clone = Arc::clone(&alives);
-            let mut node_connections = self.node_connections.clone();
+        for host in self.probe_indirectly(unreachable).await {
+            let failures = self.membership.record_failure(&host);
+            let phi = self.phi.phi(&host);
+            let suspicious = match (settings.phi_threshold, phi) {
+                (Some(threshold), Some(phi)) => phi >= threshold,
+                _ => failures >= settings.failures_before_dead,
+            };
+            if suspicious {
+                self.membership.suspect(&host);
+            } else if let (true, Some(phi)) = (self.membership.is_alive(&host), phi) {
+                log!(
+                    "-> Host \"{}\" missed a probe, but its silence isn't suspicious yet (phi {:.1})",
+                    host,
+                    phi
+                );
+            } else if self.membership.is_alive(&host) {
+                log!(
+                    "-> Host \"{}\" missed {} of {} probes before being suspected",
+                    host,
+                    failures,
+                    settings.failures_before_dead
+                );
+            }
+        }
+        for host in self.membership.expire_suspects() {
+            self.phi.forget(&host);
+            log!(
+                "-> Host \"{}\" didn't refute the suspicion, declaring it dead",
+                host
+            );
+        }
 
-            let handle = task::spawn(async move {
-                let host_name = host_clone.name.clone();
-                let host_priority = host_clone.priority;
+        let alives = self.refresh_alives(&settings);
 
+        for member in self.membership.members() {
+            if self.damping.is_held_back(&member.node.name) {
                 log!(
-                    "Checking: {}:{}",
-                    &host_clone.ip,
-                    &host_clone.port
+                    "-> Host \"{}\" is back, but not counted until it has been up for a while",
+                    member.node.name
                 );
+            }
+            log!(
+                "-> Host \"{}\" with priority {} is {:?}",
+                member.node.name,
+                member.node.priority,
+                member.state,
+            );
+        }
 
-                let alive = task::spawn_blocking(move || node_connections.ping(&host_clone))
-                    .await
-                    .unwrap();
+        alives
+    }
 
-                if alive {
+    /// Decides which peers count as up. Uses the config as it is now: nodes can be admitted,
+    /// learned from a peer's config or reloaded at runtime, also while we were probing.
+    fn refresh_alives(&mut self, settings: &FailureDetection) -> u8 {
+        let (local_name, nodes) = {
+            let config = self.config.lock().unwrap();
+            (config.config_metadata.name.clone(), config.nodes.clone())
+        };
+        let now = Instant::now();
+        self.alives = nodes
+            .iter()
+            .filter(|host| host.name != local_name)
+            .map(|host| {
+                let alive = self.damping.observe(
+                    &host.name,
+                    self.membership.is_alive(&host.name),
+                    settings,
+                    now,
+                );
+                (host.name.clone(), alive)
+            })
+            .collect();
+        self.alive_count()
+    }
+
+    fn alive_count(&self) -> u8 {
+        self.alives.values().filter(|&&alive| alive).count() as u8
+    }
+
+    fn counts_as_alive(&self, name: &str) -> bool {
+        self.alives.get(name).copied().unwrap_or(false)
+    }
+
+    /// Alive members whose silence went past the phi threshold, if one is set
+    fn overdue(&self, settings: &FailureDetection) -> Vec<ProviderNode> {
+        let Some(threshold) = settings.phi_threshold else {
+            return vec![];
+        };
+        self.membership
+            .members()
+            .into_iter()
+            .filter(|member| self.membership.is_alive(&member.node.name))
+            .filter(|member| {
+                self.phi
+                    .phi(&member.node.name)
+                    .is_some_and(|phi| phi >= threshold)
+            })
+            .map(|member| member.node)
+            .collect()
+    }
+
+    /// Before suspecting hosts we couldn't reach, asks up to `INDIRECT_PROBES` alive peers
+    /// to ping them for us. A broken link between us and a host shouldn't count as the host
+    /// being down. Returns the hosts nobody could reach.
+    ///
+    /// Members that weren't admitted aren't asked: they could keep a dead node alive forever.
+    async fn probe_indirectly(&mut self, unreachable: Vec<ProviderNode>) -> Vec<String> {
+        let helpers: Vec<String> = self
+            .alive_peers()
+            .into_iter()
+            .map(|node| node.name)
+            .collect();
+
+        let probes = unreachable.into_iter().map(|host| {
+            let helpers = &helpers;
+            let node_connections = &self.node_connections;
+            async move {
+                let vouched = node_connections
+                    .indirect_ping(&host, helpers, INDIRECT_PROBES)
+                    .await;
+                (host.name, vouched)
+            }
+        });
+
+        let mut dead = Vec::new();
+        for (name, vouched) in join_all(probes).await {
+            match vouched {
+                Some(helper) => {
                     log!(
-                        "-> Alive: host \"{}\" with priority {}",
-                        host_name,
-                        host_priority,
+                        "-> Host \"{}\" is unreachable from here, but \"{}\" can reach it",
+                        name,
+                        helper
                     );
-                    let mut count = alives_clone.lock().unwrap();
-                    *count += 1;
-                } else {
-                    log!(
-                        "-> Host \"{}\" with priority {} is dead",
-                        host_name,
-                        host_priority,
+                    self.membership.record_ack(&name);
+                }
+                None => dead.push(name),
+            }
+        }
+        dead
+    }
+
+    /// Stops every service and tells the cluster we're leaving, so nobody has to wait for
+    /// suspicion timeouts to take over.
+    pub async fn leave(&mut self) {
+        for service in self.service_names() {
+            if self.service(&service).alive {
+                self.step_down(&service).await;
+            }
+        }
+
+        let update = self.membership.leave();
+        let config_version = self
+            .config
+            .lock()
+            .unwrap()
+            .config_metadata
+            .last_updated
+            .clone();
+        let (term, terms) = self.leases.terms();
+        let goodbyes = self.alive_peers().into_iter().map(|node| {
+            let updates = vec![update.clone()];
+            let config_version = config_version.clone();
+            let terms = terms.clone();
+            let node_connections = &self.node_connections;
+            async move {
+                node_connections
+                    .gossip(&node, updates, config_version, term, terms)
+                    .await
+            }
+        });
+        join_all(goodbyes).await;
+    }
+
+    fn spawn(&mut self, service: &str) {
+        let events = self.events.clone();
+        let state = self.service_mut(service);
+        let supervisor = Supervisor::start(&state.config, events);
+        state.supervisor = Some(Arc::new(Mutex::new(supervisor)));
+    }
+
+    /// Restarts the process of `service` if it exited. If it keeps failing, we give up on the
+    /// service and step down so another node gets to run it. A process we couldn't stop is
+    /// stopped again.
+    async fn supervise(&mut self, service: &str) {
+        let state = self.service(service);
+        let Some(supervisor) = &state.supervisor else {
+            return;
+        };
+        let (health, restarts) = {
+            let mut supervisor = supervisor.lock().unwrap();
+            let health = supervisor.check(&state.config.execution, Instant::now());
+            (health, supervisor.restarts())
+        };
+        match health {
+            Health::Running | Health::Restarting => return,
+            Health::Stopping => {
+                log!("-> Trying again to stop \"{}\"", service);
+                self.step_down(service).await;
+                return;
+            }
+            Health::GaveUp => (),
+        }
+
+        self.events.emit(service, Event::GaveUp { restarts });
+        self.membership.give_up(service);
+        self.step_down(service).await;
+    }
+
+    /// Starts or stops `service` depending on who else is alive. Running it takes a lease
+    /// from the alive peers, which has to be renewed every heartbeat.
+    async fn elect(&mut self, service: &str, alives: u8) {
+        let preference = self.membership.preference_for(service);
+        let state = self.service(service);
+        let alive = state.alive;
+        let (quorum, eligible) = {
+            let config = self.config.lock().unwrap();
+            let eligible = config
+                .nodes
+                .iter()
+                .find(|d| d.name == config.config_metadata.name)
+                .and_then(|d| state.config.priority(d))
+                .is_some();
+            (config.quorum, eligible)
+        };
+        if !eligible && !alive {
+            return;
+        }
+        if quorum && !self.has_quorum() && (alive || preference != Preference::Drained) {
+            log!(
+                "-> No quorum for \"{}\": only {} of {} nodes reachable",
+                service,
+                self.reachable(),
+                self.config.lock().unwrap().nodes.len()
+            );
+        }
+
+        let wants_to_run = eligible
+            && preference != Preference::Drained
+            && (!quorum || self.has_quorum())
+            && (alives == 0 || !self.outranked(service))
+            && (alive || !self.defers_to_active_peer(service));
+
+        let active = self.active_peer(service).filter(|_| !alive && wants_to_run);
+        if active.is_none() {
+            self.service_mut(service).handover = None;
+        }
+
+        if !alive && wants_to_run {
+            let mut silent = self.silent_actives(service);
+            if let Some(active) = active {
+                match self.handover_from(service, &active) {
+                    Some(Some(true)) => (),
+                    Some(Some(false)) | None => return,
+                    Some(None) => silent.push(active),
+                }
+            }
+            if self.acquire_lease(service).await {
+                if !self.fence(service, &silent).await {
+                    // Hands the lease back, we'll try again next heartbeat
+                    self.step_down(service).await;
+                    return;
+                }
+                log!("\n-> Node switching to alive for \"{}\"", service);
+                let state = self.service_mut(service);
+                state.alive = true;
+                let term = state.leases.term();
+                self.membership.set_active_term(service, Some(term));
+                self.spawn(service);
+            }
+        } else if alive && !wants_to_run {
+            self.step_down(service).await;
+        } else if alive && !self.service(service).leases.holds_lease() {
+            log!(
+                "-> Lost the lease of \"{}\" in term {}",
+                service,
+                self.service(service).leases.term()
+            );
+            self.step_down(service).await;
+        }
+    }
+
+    /// The alive peer running `service`, if any
+    fn active_peer(&self, service: &str) -> Option<ProviderNode> {
+        self.admitted_members()
+            .into_iter()
+            .find(|m| m.active_term(service).is_some() && self.membership.is_alive(&m.node.name))
+            .map(|m| m.node)
+    }
+
+    /// Peers that were running `service` when we last heard from them
+    fn silent_actives(&self, service: &str) -> Vec<ProviderNode> {
+        self.admitted_members()
+            .into_iter()
+            .filter(|m| m.active_term(service).is_some() && !self.membership.is_alive(&m.node.name))
+            .map(|m| m.node)
+            .collect()
+    }
+
+    /// Runs the configured fencing actions against every node in `silent`. Returns whether
+    /// all of them were fenced.
+    async fn fence(&mut self, service: &str, silent: &[ProviderNode]) -> bool {
+        let actions = self.config.lock().unwrap().fencing.clone();
+        if actions.is_empty() {
+            return true;
+        }
+
+        let preference = self.membership.preference_for(service);
+        for node in silent {
+            log!(
+                "-> Fencing \"{}\" before taking \"{}\" over",
+                node.name,
+                service
+            );
+            let fenced =
+                fencing::fence(&actions, node, &self.node_connections, service, preference);
+            match self.renewing(fenced).await {
+                Ok(()) => {
+                    self.membership.record_stepped_down(&node.name, service);
+                    self.events.emit(
+                        service,
+                        Event::Fenced {
+                            node: node.name.clone(),
+                        },
+                    );
+                }
+                Err(e) => {
+                    self.events.emit(
+                        service,
+                        Event::FencingFailed {
+                            node: node.name.clone(),
+                            error: format!("{:#}", e),
+                        },
                     );
+                    return false;
                 }
+            }
+        }
+        true
+    }
+
+    /// Asks `active` to stop `service` before we start it, so the two never overlap. Returns
+    /// `None` until it answered, which may take a while: the request runs in the background
+    /// and wakes the heartbeat once it's done. The answer is `None` if it didn't come in time:
+    /// then its lease has to run out before anyone grants us ours, and it gets fenced.
+    fn handover_from(&mut self, service: &str, active: &ProviderNode) -> Option<Option<bool>> {
+        let pending = self
+            .service(service)
+            .handover
+            .clone()
+            .filter(|pending| pending.active == active.name);
+        let Some(pending) = pending else {
+            log!("-> Asking \"{}\" to hand \"{}\" over", active.name, service);
+            let answer = Arc::new(OnceLock::new());
+            self.service_mut(service).handover = Some(PendingHandover {
+                active: active.name.clone(),
+                answer: answer.clone(),
+            });
+
+            let preference = self.membership.preference_for(service);
+            let (node_connections, wake) = (self.node_connections.clone(), self.wake.clone());
+            let (active, service) = (active.clone(), service.to_string());
+            tokio::spawn(async move {
+                let stopped = node_connections
+                    .handover(&active, &service, preference)
+                    .await;
+                let _ = answer.set(stopped);
+                wake.notify_one();
             });
+            return None;
+        };
+        let Some(&answer) = pending.answer.get() else {
+            log!(
+                "-> Waiting for \"{}\" to hand \"{}\" over",
+                active.name,
+                service
+            );
+            return None;
+        };
+        self.service_mut(service).handover = None;
 
-            handles.push(handle);
+        Some(match answer {
+            Some(true) => {
+                log!("-> \"{}\" stopped \"{}\"", active.name, service);
+                self.membership.record_stepped_down(&active.name, service);
+                Some(true)
+            }
+            Some(false) => {
+                log!("-> \"{}\" isn't handing \"{}\" over", active.name, service);
+                Some(false)
+            }
+            None => {
+                log!(
+                    "-> \"{}\" didn't confirm the handover, waiting for its lease to run out",
+                    active.name
+                );
+                None
+            }
+        })
+    }
+
+    /// Answers a handover request from `from`: stops `service` if `from` may take it over
+    /// from us. Returns whether it's stopped.
+    pub async fn hand_over(&mut self, from: &str, service: &str, preference: Preference) -> bool {
+        let Some(state) = self.services.iter().find(|s| s.config.name == service) else {
+            return true;
+        };
+        if !state.alive {
+            return true;
         }
 
-        join_all(handles).await;
+        let allowed = {
+            let config = self.config.lock().unwrap();
+            let local_rank = config
+                .nodes
+                .iter()
+                .find(|d| d.name == config.config_metadata.name)
+                .and_then(|d| {
+                    rank(
+                        self.membership.preference_for(service),
+                        d,
+                        &state.config,
+                        config.tie_breaker,
+                    )
+                });
+            config
+                .nodes
+                .iter()
+                .find(|d| d.name == from)
+                .is_some_and(|host| {
+                    rank(preference, host, &state.config, config.tie_breaker) > local_rank
+                        && self.may_preempt(&config.preemption, host, preference)
+                })
+        };
+        if !allowed {
+            log!("-> Not handing \"{}\" over to \"{}\"", service, from);
+            return false;
+        }
 
-        let alives = *alives.lock().unwrap();
-        alives
+        log!("-> Handing \"{}\" over to \"{}\"", service, from);
+        self.step_down(service).await
     }
 
-    fn spawn(&mut self) {
-        let process = Process::new(&self.config.lock().unwrap());
-        self.process = Some(Arc::new(Mutex::new(process)));
+    /// Starts a new term for `service` and asks every alive peer for the lease.
+    async fn acquire_lease(&mut self, service: &str) -> bool {
+        let local_name = self.config.lock().unwrap().config_metadata.name.clone();
+        let leases = self.service(service).leases.clone();
+        let term = leases.start_election(&local_name);
+        log!(
+            "-> Asking for the lease of \"{}\" in term {}",
+            service,
+            term
+        );
+
+        let asked_at = Instant::now();
+        if !self.collect_votes(service, term).await {
+            return false;
+        }
+        leases.acquired(term, asked_at)
     }
 
-    pub async fn heartbeat(&mut self) {
-        log!("\n====> Heartbeat");
+    /// Waits for `operation` on one service, which may take longer than a lease lasts (e.g.
+    /// stopping its process or fencing), renewing our leases every heartbeat meanwhile so the
+    /// other services keep theirs. Whatever we run without a lease is killed right away.
+    async fn renewing<T>(&self, operation: impl Future<Output = T>) -> T {
+        let interval = self
+            .config
+            .lock()
+            .unwrap()
+            .failure_detection
+            .heartbeat_interval();
+        let mut operation = pin!(operation);
+        loop {
+            let due = *self.renewed_at.lock().unwrap() + interval;
+            tokio::select! {
+                result = &mut operation => return result,
+                _ = tokio::time::sleep_until(due.into()) => (),
+            }
 
-        let alives = self.check_hosts().await;
-        log!("\nAll hosts checked!");
+            // Neither is cut short: both talk to our peers
+            let renewal = pin!(self.renew_leases());
+            let done = match select(operation.as_mut(), renewal).await {
+                Either::Left((result, renewal)) => {
+                    renewal.await;
+                    Some(result)
+                }
+                Either::Right(_) => None,
+            };
+            self.kill_unleased();
+            if let Some(result) = done {
+                return result;
+            }
+        }
+    }
 
-        log!("-> Alives: {}", alives);
-        if !self.alive
-            && (alives == 0 || {
-                // There are nodes alive with less priority
-                let config_guard = self.config.lock().unwrap();
-                assert!(config_guard.nodes.len() == self.alives.len());
-                let local_priority = config_guard
-                    .nodes
-                    .iter()
-                    .find(|d| d.name == config_guard.config_metadata.name)
-                    .map(|d| d.priority)
-                    .unwrap_or(0);
+    /// Kills the processes of the services we run without a lease, e.g. because a renewal was
+    /// refused while we were busy with another service. They're stepped down once we get to
+    /// them.
+    fn kill_unleased(&self) {
+        for state in &self.services {
+            if !state.alive || state.leases.holds_lease() {
+                continue;
+            }
+            // Locked while it's being stopped already
+            if let Some(Ok(mut supervisor)) = state.supervisor.as_ref().map(|s| s.try_lock()) {
+                log!("-> Lost the lease of \"{}\", killing it", state.config.name);
+                supervisor.kill();
+            }
+        }
+    }
+
+    /// Renews every lease we hold, for all services at once, so renewing doesn't take longer
+    /// the more services we run. A refused renewal drops the lease, and `elect` steps the
+    /// service down.
+    async fn renew_leases(&self) {
+        *self.renewed_at.lock().unwrap() = Instant::now();
+        let held: Vec<(String, Leases)> = self
+            .services
+            .iter()
+            .filter(|s| s.leases.holds_lease())
+            .map(|s| (s.config.name.clone(), s.leases.clone()))
+            .collect();
+        let renewals = held.iter().map(|(service, leases)| async move {
+            let term = leases.term();
+            let asked_at = Instant::now();
+            if !(self.collect_votes(service, term).await && leases.acquired(term, asked_at)) {
+                leases.drop_lease();
+            }
+        });
+        join_all(renewals).await;
+    }
+
+    /// Whether no alive peer refused the lease of `service` in `term`. Peers that don't answer
+    /// are left to the failure detector, unless quorum mode asks for a majority of grants.
+    async fn collect_votes(&self, service: &str, term: u64) -> bool {
+        let peers = self.alive_peers();
+        let requests = peers
+            .iter()
+            .map(|node| self.node_connections.request_lease(node, service, term));
+        let votes = join_all(requests).await;
+
+        // Our own vote
+        let mut granted = 1;
+        let leases = &self.service(service).leases;
+        for (node, vote) in peers.iter().zip(votes) {
+            let Some(vote) = vote else {
+                continue;
+            };
+            if leases.observe(vote.term) {
+                log!("-> \"{}\" is in term {} already", node.name, vote.term);
+                return false;
+            }
+            if !vote.granted && self.stepping_down(service, &node.name, vote.holder.as_deref()) {
+                log!(
+                    "-> \"{}\" refused the lease of term {}, but it's on behalf of a node stepping down",
+                    node.name,
+                    term
+                );
+                continue;
+            }
+            if !vote.granted {
+                log!(
+                    "-> \"{}\" refused the lease of term {} (granted to {:?})",
+                    node.name,
+                    term,
+                    vote.holder
+                );
+                return false;
+            }
+            granted += 1;
+        }
+
+        let (quorum, nodes) = {
+            let config = self.config.lock().unwrap();
+            (config.quorum, config.nodes.len())
+        };
+        if quorum && granted * 2 <= nodes {
+            log!(
+                "-> Only {} of {} nodes granted the lease of term {}",
+                granted,
+                nodes,
+                term
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Whether a refusal from `voter` for `holder` is down to a split brain over `service`
+    /// we're resolving
+    fn stepping_down(&self, service: &str, voter: &str, holder: Option<&str>) -> bool {
+        let split_brain = &self.service(service).split_brain;
+        split_brain.iter().any(|name| name == voter)
+            || holder.is_some_and(|holder| split_brain.iter().any(|name| name == holder))
+    }
+
+    /// Settles on a single active node when alive peers announce they're running `service`
+    /// too, e.g. after a partition healed. Every active node picks the same one: first in the
+    /// election order, or for nodes outside of it (drained ones), the highest term, then the
+    /// lowest name. The others step down.
+    async fn resolve_split_brain(&mut self, service: &str) {
+        let local_term = match self.membership.active_term(service) {
+            Some(term) if self.service(service).alive => term,
+            _ => {
+                self.service_mut(service).split_brain.clear();
+                return;
+            }
+        };
+        let (local, tie_breaker) = {
+            let config = self.config.lock().unwrap();
+            let local = config
+                .nodes
+                .iter()
+                .find(|d| d.name == config.config_metadata.name)
+                .cloned()
+                .expect("Local node missing from config");
+            (local, config.tie_breaker)
+        };
+
+        let others: Vec<_> = self
+            .admitted_members()
+            .into_iter()
+            .filter(|m| m.active_term(service).is_some() && self.membership.is_alive(&m.node.name))
+            .collect();
+        let mut names: Vec<String> = others.iter().map(|m| m.node.name.clone()).collect();
+        names.sort();
+        if names.is_empty() {
+            self.service_mut(service).split_brain.clear();
+            return;
+        }
+
+        let config = &self.service(service).config;
+        let key = |term: u64, preference: Preference, node: &ProviderNode| {
+            (
+                rank(preference, node, config, tie_breaker),
+                term,
+                Reverse(node.name.clone()),
+            )
+        };
+        let (kept, term) = others
+            .iter()
+            .map(|m| {
+                let term = m.active_term(service).unwrap_or_default();
+                (key(term, m.preference_for(service), &m.node), term)
+            })
+            .chain(std::iter::once((
+                key(local_term, self.membership.preference_for(service), &local),
+                local_term,
+            )))
+            .max()
+            .map(|((_, _, Reverse(name)), term)| (name, term))
+            .unwrap();
+
+        if names != self.service(service).split_brain {
+            let mut active = names.clone();
+            active.push(local.name.clone());
+            active.sort();
+            self.events.emit(
+                service,
+                Event::SplitBrain {
+                    active,
+                    kept: kept.clone(),
+                    term,
+                },
+            );
+        }
+
+        if kept == local.name {
+            self.service_mut(service).split_brain = names;
+        } else {
+            log!(
+                "-> Stepping down from \"{}\" in favour of \"{}\"",
+                service,
+                kept
+            );
+            self.service_mut(service).split_brain.clear();
+            self.step_down(service).await;
+        }
+    }
+
+    /// We and the peers we can reach
+    fn reachable(&self) -> usize {
+        1 + self.alive_count() as usize
+    }
+
+    /// Whether we can reach a strict majority of the configured nodes
+    fn has_quorum(&self) -> bool {
+        self.reachable() * 2 > self.config.lock().unwrap().nodes.len()
+    }
+
+    /// Stops `service` and hands its lease back. Returns whether it's stopped: we only count
+    /// as standby, and release the lease, once its process group is gone. Otherwise we stay
+    /// active and try again next heartbeat.
+    async fn step_down(&mut self, service: &str) -> bool {
+        let state = self.service_mut(service);
+        if let Some(supervisor) = state.supervisor.clone() {
+            let policy = state.config.execution.stop.clone();
+            let stopped = self
+                .renewing(tokio::task::spawn_blocking(move || {
+                    supervisor.lock().unwrap().stop(&policy)
+                }))
+                .await;
+            match stopped {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => {
+                    self.events.emit(
+                        service,
+                        Event::StopFailed {
+                            error: format!("{:#}", e),
+                        },
+                    );
+                    return false;
+                }
+                Err(e) => {
+                    log!("-> Stopping \"{}\" panicked: {}", service, e);
+                    return false;
+                }
+            }
+        }
+        let state = self.service_mut(service);
+        state.supervisor = None;
+        state.alive = false;
+        let leases = state.leases.clone();
+        self.membership.set_active_term(service, None);
+
+        let term = leases.term();
+        leases.drop_lease();
+        let peers = self.alive_peers();
+        join_all(
+            peers
+                .iter()
+                .map(|node| self.node_connections.release_lease(node, service, term)),
+        )
+        .await;
+        true
+    }
+
+    fn alive_peers(&self) -> Vec<ProviderNode> {
+        self.admitted_members()
+            .into_iter()
+            .filter(|member| self.membership.is_alive(&member.node.name))
+            .map(|member| member.node)
+            .collect()
+    }
 
-                !config_guard
+    /// Members that are in `nodes`, with their entry there rather than what they gossiped about
+    /// themselves, so a member can't outrank others by claiming a higher priority. Nodes only
+    /// heard about through gossip take no part in elections until a trusted peer vouched for
+    /// them.
+    fn admitted_members(&self) -> Vec<Member> {
+        let config = self.config.lock().unwrap();
+        self.membership
+            .members()
+            .into_iter()
+            .filter_map(|mut m| {
+                m.node = config.nodes.iter().find(|d| d.name == m.node.name)?.clone();
+                Some(m)
+            })
+            .collect()
+    }
+
+    /// Whether an alive peer should run `service` rather than us. While we're active, that's
+    /// up to the preemption policy.
+    fn outranked(&self, service: &str) -> bool {
+        let state = self.service(service);
+        let config_guard = self.config.lock().unwrap();
+        let local_rank = config_guard
+            .nodes
+            .iter()
+            .find(|d| d.name == config_guard.config_metadata.name)
+            .and_then(|d| {
+                rank(
+                    self.membership.preference_for(service),
+                    d,
+                    &state.config,
+                    config_guard.tie_breaker,
+                )
+            });
+
+        let members = self.membership.members();
+        config_guard.nodes.iter().any(|host| {
+            let alive = self.counts_as_alive(&host.name);
+            let preference = members
+                .iter()
+                .find(|m| m.node.name == host.name)
+                .map(|m| m.preference_for(service))
+                .unwrap_or_default();
+            alive
+                && rank(preference, host, &state.config, config_guard.tie_breaker) > local_rank
+                && (!state.alive || self.may_preempt(&config_guard.preemption, host, preference))
+        })
+    }
+
+    /// Whether `host`, which outranks us, may take the process over from us now.
+    fn may_preempt(
+        &self,
+        preemption: &Preemption,
+        host: &ProviderNode,
+        preference: Preference,
+    ) -> bool {
+        // Operators promoting a node want it to take over
+        if preference == Preference::Promoted {
+            return true;
+        }
+
+        match preemption.mode {
+            PreemptionMode::Immediate => true,
+            PreemptionMode::Never => {
+                log!(
+                    "-> \"{}\" outranks us, but we keep the process (preemption: never)",
+                    host.name
+                );
+                false
+            }
+            PreemptionMode::Delayed => {
+                let up_for = self
+                    .damping
+                    .up_for(&host.name, Instant::now())
+                    .unwrap_or_default();
+                let healthy_for = Duration::from_secs(preemption.healthy_for_secs.unwrap_or(0));
+                if up_for < healthy_for {
+                    log!(
+                        "-> \"{}\" outranks us, handing over once it's been up for {}s ({}s so far)",
+                        host.name,
+                        healthy_for.as_secs(),
+                        up_for.as_secs()
+                    );
+                    return false;
+                }
+                if let Some(window) = preemption.window {
+                    if !window.contains(Utc::now().time()) {
+                        log!(
+                            "-> \"{}\" outranks us, handing over during the maintenance window {}",
+                            host.name,
+                            window
+                        );
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Whether an alive peer is running `service` already and we shouldn't take it over: we
+    /// don't outrank it, or the preemption policy leaves the handover to the active node.
+    fn defers_to_active_peer(&self, service: &str) -> bool {
+        let config_service = &self.service(service).config;
+        let preference = self.membership.preference_for(service);
+        let (local_rank, preemption, tie_breaker) = {
+            let config = self.config.lock().unwrap();
+            (
+                config
                     .nodes
                     .iter()
-                    .zip(self.alives.iter())
-                    .any(|(host, &alive)| alive && host.priority > local_priority)
+                    .find(|d| d.name == config.config_metadata.name)
+                    .and_then(|d| rank(preference, d, config_service, config.tie_breaker)),
+                config.preemption.mode,
+                config.tie_breaker,
+            )
+        };
+        let takes_over =
+            preemption == PreemptionMode::Immediate || preference == Preference::Promoted;
+
+        self.admitted_members().iter().any(|m| {
+            m.active_term(service).is_some()
+                && self.membership.is_alive(&m.node.name)
+                && (rank(
+                    m.preference_for(service),
+                    &m.node,
+                    config_service,
+                    tie_breaker,
+                ) >= local_rank
+                    || !takes_over)
+        })
+    }
+
+    pub fn status(&self) -> Status {
+        let config_guard = self.config.lock().unwrap();
+        let members = self.membership.members();
+
+        let peers = config_guard
+            .nodes
+            .iter()
+            .filter(|host| host.name != config_guard.config_metadata.name)
+            .map(|host| {
+                let alive = self.counts_as_alive(&host.name);
+                let member = members.iter().find(|m| m.node.name == host.name);
+                PeerStatus {
+                    name: host.name.clone(),
+                    address: format!("{}:{}", host.ip, host.port),
+                    priority: host.priority,
+                    role: host.role,
+                    alive,
+                    state: member.map(|m| m.state),
+                    preference: member.map(|m| m.preference).unwrap_or_default(),
+                    phi: self.phi.phi(&host.name),
+                }
             })
-        {
-            log!("\n-> Node switching to alive");
-            self.alive = true;
-            self.spawn();
-        } else {
-            // Hosts alive
-            // First check configs and then kill or otherwise?
-            let config_guard = self.config.lock().unwrap();
-            let local_priority = config_guard
+            .collect();
+
+        let services = self
+            .services
+            .iter()
+            .map(|state| {
+                let service = &state.config.name;
+                ServiceStatus {
+                    name: service.clone(),
+                    role: if state.alive {
+                        Role::Active
+                    } else {
+                        Role::Standby
+                    },
+                    term: state.leases.term(),
+                    pid: state
+                        .supervisor
+                        .as_ref()
+                        .and_then(|s| s.lock().unwrap().pid()),
+                    active: if state.alive {
+                        Some(config_guard.config_metadata.name.clone())
+                    } else {
+                        members
+                            .iter()
+                            .find(|m| {
+                                m.active_term(service).is_some()
+                                    && self.membership.is_alive(&m.node.name)
+                                    && config_guard.nodes.iter().any(|d| d.name == m.node.name)
+                            })
+                            .map(|m| m.node.name.clone())
+                    },
+                }
+            })
+            .collect();
+
+        Status {
+            node: config_guard.config_metadata.name.clone(),
+            node_role: config_guard
                 .nodes
                 .iter()
                 .find(|d| d.name == config_guard.config_metadata.name)
-                .map(|d| d.priority)
-                .unwrap_or(0);
+                .map(|d| d.role)
+                .unwrap_or_default(),
+            preference: self.membership.preference(),
+            quorum: config_guard
+                .quorum
+                .then(|| self.reachable() * 2 > config_guard.nodes.len()),
+            services,
+            peers,
+            events: self.events.recent(),
+        }
+    }
 
-            if self.process.is_some()
-                && config_guard
-                    .nodes
-                    .iter()
-                    .any(|d| d.priority > local_priority)
-            {
-                // Clean up
-                self.alive = false;
-                if let Some(p) = &self.process {
-                    {
-                        let mut process = p.lock().unwrap();
-                        process.kill();
-                    }
-                    self.process = None;
+    /// Answers a request from the control socket. Preference changes take effect right away
+    /// and reach peers with the next gossip.
+    pub async fn control(&mut self, request: ControlRequest) -> ControlResponse {
+        let preference = match request {
+            ControlRequest::Status => return ControlResponse::Status(self.status()),
+            ControlRequest::Reload => {
+                return ControlResponse::Error {
+                    message: "reloading is handled by the daemon".to_string(),
                 }
             }
+            ControlRequest::Drain => Preference::Drained,
+            ControlRequest::Promote => Preference::Promoted,
+            ControlRequest::Demote => Preference::Demoted,
+            ControlRequest::Resume => Preference::Normal,
+        };
+
+        log!(
+            "-> Preference set to {:?} through the control socket",
+            preference
+        );
+        self.membership.set_preference(preference);
+        let alives = self.alive_count();
+        for service in self.service_names() {
+            self.elect(&service, alives).await;
         }
 
+        let active: Vec<String> = self
+            .services
+            .iter()
+            .filter(|s| s.alive)
+            .map(|s| format!("\"{}\"", s.config.name))
+            .collect();
+        ControlResponse::Done {
+            message: if active.is_empty() {
+                format!("{:?}, standby", preference)
+            } else {
+                format!("{:?}, active for {}", preference, active.join(", "))
+            },
+        }
+    }
+
+    pub async fn heartbeat(&mut self) {
+        log!("\n====> Heartbeat");
+
+        let alives = self.check_hosts().await;
+        log!("\nAll hosts checked!");
+
+        log!("-> Alives: {}", alives);
+        for pending in self.pending_verifications.list() {
+            log!(
+                "-> Pending verification: {} from {} ({:?}, vouched for by {:?})",
+                pending.source,
+                pending.remote_addr,
+                pending.state,
+                pending.redirect_node,
+            );
+        }
+        self.sync_services().await;
+        self.renew_leases().await;
+        for service in self.service_names() {
+            self.supervise(&service).await;
+            self.resolve_split_brain(&service).await;
+            self.elect(&service, alives).await;
+        }
+        process::reap_orphans();
+
         log!("====> Hearbeat end");
     }
 }
+
+/// Position in the election order, the highest runs the process. No two nodes share one.
+type Rank = (u8, u32, Reverse<(u64, String)>);
+
+/// Election order of `service`: preference first, then the node's priority for the service,
+/// then `tie_breaker`. Nodes that may not run the service, witnesses among them, and drained
+/// nodes never do.
+fn rank(
+    preference: Preference,
+    node: &ProviderNode,
+    service: &Service,
+    tie_breaker: TieBreaker,
+) -> Option<Rank> {
+    let priority = service.priority(node)?;
+    let class = match preference {
+        Preference::Drained => return None,
+        Preference::Demoted => 0,
+        Preference::Normal => 1,
+        Preference::Promoted => 2,
+    };
+    let id = match tie_breaker {
+        TieBreaker::Name => 0,
+        // Nodes without an id go after those with one
+        TieBreaker::Id => node.id.unwrap_or(u64::MAX),
+    };
+    Some((class, priority, Reverse((id, node.name.clone()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::DEFAULT_SERVICE,
+        lease::LEASE_DURATION,
+        membership::{Activity, MemberState, MemberUpdate},
+        parser::Parser,
+        protocol::{self, Envelope, Request, Response},
+        supervisor::Health,
+    };
+    use tokio::net::TcpListener;
+
+    fn node(local: &str) -> Node {
+        let yaml = format!(
+            r#"
+nodes:
+- name: a
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: b
+  ip: 127.0.0.1
+  port: 8081
+  priority: 50
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: {local}
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+"#
+        );
+        let config = Parser::new(yaml.as_bytes()).parse(None).unwrap();
+        Node::with_leases(Arc::new(Mutex::new(config)), ServiceLeases::in_memory())
+    }
+
+    #[test]
+    fn test_node_joining_during_a_heartbeat() {
+        let mut node = node("b");
+        let settings = FailureDetection::default();
+        assert_eq!(node.refresh_alives(&settings), 0);
+        assert!(!node.outranked(DEFAULT_SERVICE));
+
+        // Gossip brings in c, which is admitted before the next heartbeat
+        let mut c = node.config.lock().unwrap().nodes[0].clone();
+        c.name = "c".to_string();
+        c.priority = 200;
+        node.membership.apply(vec![MemberUpdate {
+            node: c.clone(),
+            state: MemberState::Alive,
+            incarnation: 1,
+            preference: Preference::Normal,
+            activity: Activity::default(),
+        }]);
+        node.config.lock().unwrap().nodes.push(c);
+
+        // Not counted until it's been seen by a heartbeat
+        assert!(!node.outranked(DEFAULT_SERVICE));
+        assert!(!node.has_quorum());
+        assert_eq!(node.refresh_alives(&settings), 1);
+        assert!(node.outranked(DEFAULT_SERVICE));
+        assert!(node.has_quorum());
+    }
+
+    #[test]
+    fn test_gossiped_node_needs_verification() {
+        let node = node("b");
+        let mut x = node.config.lock().unwrap().nodes[0].clone();
+        x.name = "x".to_string();
+        let joined = node.membership.apply(vec![MemberUpdate {
+            node: x,
+            state: MemberState::Alive,
+            incarnation: 1,
+            preference: Preference::Normal,
+            activity: Activity {
+                active_term: Some(1),
+                ..Activity::default()
+            },
+        }]);
+        assert_eq!(joined.len(), 1);
+
+        // Until a trusted peer vouches for it, it can't keep us from running the process
+        assert_eq!(node.config.lock().unwrap().nodes.len(), 2);
+        assert!(node.active_peer(DEFAULT_SERVICE).is_none());
+        assert!(!node.defers_to_active_peer(DEFAULT_SERVICE));
+        assert!(node.alive_peers().is_empty());
+    }
+
+    /// A peer answering requests with `answer`, after `delay`. Returns its port.
+    async fn stub(
+        delay: Duration,
+        answer: impl Fn(Request) -> Response + Clone + Send + 'static,
+    ) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let answer = answer.clone();
+                tokio::spawn(async move {
+                    while let Ok(request) =
+                        protocol::read_frame_async::<_, Envelope<Request>>(&mut stream).await
+                    {
+                        tokio::time::sleep(delay).await;
+                        let envelope = Envelope::new(request.id, answer(request.body));
+                        if protocol::write_frame_async(&mut stream, &envelope)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    /// A member called `name` that answers every `PingReq` with `alive`, and is admitted if
+    /// `admitted`
+    async fn helper(node: &Node, name: &str, alive: bool, admitted: bool) {
+        let port = stub(Duration::ZERO, move |request| match request {
+            Request::PingReq { target } => Response::Probe { target, alive },
+            _ => Response::Pong,
+        })
+        .await;
+
+        let mut helper = node.config.lock().unwrap().nodes[0].clone();
+        helper.name = name.to_string();
+        helper.port = port as u32;
+        node.membership.apply(vec![MemberUpdate {
+            node: helper.clone(),
+            state: MemberState::Alive,
+            incarnation: 1,
+            preference: Preference::Normal,
+            activity: Activity::default(),
+        }]);
+        node.node_connections
+            .create_node_connection(&helper)
+            .await
+            .unwrap();
+        if admitted {
+            node.config.lock().unwrap().nodes.push(helper);
+        }
+    }
+
+    /// `a`, listening on a port nothing listens on
+    fn unreachable(node: &Node) -> ProviderNode {
+        let mut host = node.config.lock().unwrap().nodes[0].clone();
+        host.port = 1;
+        host
+    }
+
+    #[tokio::test]
+    async fn test_helper_reaches_unreachable_host() {
+        let mut node = node("b");
+        helper(&node, "c", false, true).await;
+        helper(&node, "d", true, true).await;
+
+        let host = unreachable(&node);
+        assert!(node.probe_indirectly(vec![host]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_helpers_to_probe_through() {
+        let mut node = node("b");
+        let host = unreachable(&node);
+        assert_eq!(node.probe_indirectly(vec![host.clone()]).await, vec!["a"]);
+
+        // A member that wasn't admitted could claim anything, so it isn't asked
+        helper(&node, "mallory", true, false).await;
+        assert_eq!(node.probe_indirectly(vec![host]).await, vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_doesnt_wait_for_a_handover() {
+        let mut node = node("b");
+        let port = stub(Duration::from_millis(300), |request| match request {
+            Request::Handover { .. } => Response::Handover { stopped: true },
+            _ => Response::Pong,
+        })
+        .await;
+        let a = {
+            let mut config = node.config.lock().unwrap();
+            config.nodes[0].port = port as u32;
+            config.nodes[0].clone()
+        };
+        node.membership.apply(vec![MemberUpdate {
+            node: a.clone(),
+            state: MemberState::Alive,
+            incarnation: 1,
+            preference: Preference::Normal,
+            activity: Activity {
+                active_term: Some(1),
+                ..Activity::default()
+            },
+        }]);
+        // Promoted, so we take the service over from "a"
+        node.membership.set_preference(Preference::Promoted);
+
+        let started = Instant::now();
+        node.elect(DEFAULT_SERVICE, 1).await;
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert!(!node.service(DEFAULT_SERVICE).alive);
+
+        // Meanwhile, "a" may ask us to hand over a service we run, e.g. because it's
+        // preferred for it, and gets its answer right away
+        assert!(
+            node.hand_over("a", DEFAULT_SERVICE, Preference::Normal)
+                .await
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), node.wake.notified())
+            .await
+            .unwrap();
+        node.elect(DEFAULT_SERVICE, 1).await;
+        assert!(node.service(DEFAULT_SERVICE).alive);
+    }
+
+    /// Makes us run the default service with a lease that runs out in `left`, and due for a
+    /// renewal
+    fn lease_running_out(node: &mut Node, left: Duration) {
+        let local = node.config.lock().unwrap().config_metadata.name.clone();
+        let leases = node.service(DEFAULT_SERVICE).leases.clone();
+        let term = leases.start_election(&local);
+        assert!(leases.acquired(term, Instant::now() - LEASE_DURATION + left));
+        node.service_mut(DEFAULT_SERVICE).alive = true;
+        *node.renewed_at.lock().unwrap() = Instant::now() - Duration::from_secs(60);
+    }
+
+    #[tokio::test]
+    async fn test_leases_renewed_during_long_operations() {
+        let mut node = node("b");
+        lease_running_out(&mut node, Duration::from_millis(300));
+
+        node.renewing(tokio::time::sleep(Duration::from_millis(600)))
+            .await;
+        assert!(node.service(DEFAULT_SERVICE).leases.holds_lease());
+    }
+
+    #[tokio::test]
+    async fn test_unleased_process_killed_during_long_operations() {
+        let mut node = node("b");
+        let port = stub(Duration::ZERO, |request| match request {
+            Request::Lease { term, .. } => Response::Lease {
+                granted: false,
+                term,
+                holder: Some("a".to_string()),
+            },
+            _ => Response::Pong,
+        })
+        .await;
+        let a = {
+            let mut config = node.config.lock().unwrap();
+            config.nodes[0].port = port as u32;
+            config.nodes[0].clone()
+        };
+        node.membership.apply(vec![MemberUpdate {
+            node: a,
+            state: MemberState::Alive,
+            incarnation: 1,
+            preference: Preference::Normal,
+            activity: Activity::default(),
+        }]);
+        node.service_mut(DEFAULT_SERVICE)
+            .config
+            .execution
+            .instructions = "sleep 100".to_string();
+        node.spawn(DEFAULT_SERVICE);
+        lease_running_out(&mut node, LEASE_DURATION / 2);
+
+        node.renewing(tokio::time::sleep(Duration::from_millis(200)))
+            .await;
+        let state = node.service(DEFAULT_SERVICE);
+        assert!(!state.leases.holds_lease());
+        let supervisor = state.supervisor.clone().unwrap();
+        let mut supervisor = supervisor.lock().unwrap();
+        assert_eq!(
+            supervisor.check(&state.config.execution, Instant::now()),
+            Health::Stopping
+        );
+        supervisor.stop(&state.config.execution.stop).unwrap();
+    }
+
+    /// Gossip from `peer`, alive and running the default service in `term`
+    fn announce(node: &Node, peer: ProviderNode, preference: Preference, term: Option<u64>) {
+        node.membership.apply(vec![MemberUpdate {
+            node: peer,
+            state: MemberState::Alive,
+            incarnation: 1,
+            preference,
+            activity: Activity {
+                active_term: term,
+                ..Activity::default()
+            },
+        }]);
+    }
+
+    fn configured(node: &Node, name: &str) -> ProviderNode {
+        let config = node.config.lock().unwrap();
+        config
+            .nodes
+            .iter()
+            .find(|d| d.name == name)
+            .unwrap()
+            .clone()
+    }
+
+    /// The configured node `name` as it gossips about itself, claiming `priority`
+    fn claiming(node: &Node, name: &str, priority: u32) -> ProviderNode {
+        let mut peer = configured(node, name);
+        peer.priority = priority;
+        peer
+    }
+
+    #[tokio::test]
+    async fn test_gossiped_priority_is_ignored() {
+        let mut node = node("a");
+        announce(
+            &node,
+            claiming(&node, "b", 1000),
+            Preference::Normal,
+            Some(1),
+        );
+        // We outrank "b" by our config, so we take the service over from it
+        assert!(!node.defers_to_active_peer(DEFAULT_SERVICE));
+
+        // And keep it if we both run it
+        node.service_mut(DEFAULT_SERVICE).alive = true;
+        node.membership.set_active_term(DEFAULT_SERVICE, Some(1));
+        node.resolve_split_brain(DEFAULT_SERVICE).await;
+        assert!(node.service(DEFAULT_SERVICE).alive);
+        assert_eq!(node.service(DEFAULT_SERVICE).split_brain, vec!["b"]);
+    }
+
+    /// Makes us run the default service in `term`, like `b` does, and resolves the split brain
+    async fn split_brain(local: &str, preference: Preference, term: u64, b: u64) -> Node {
+        let mut node = node(local);
+        node.membership.set_preference(preference);
+        let peer = if local == "a" { "b" } else { "a" };
+        announce(&node, configured(&node, peer), preference, Some(b));
+        node.service_mut(DEFAULT_SERVICE).alive = true;
+        node.membership.set_active_term(DEFAULT_SERVICE, Some(term));
+        node.resolve_split_brain(DEFAULT_SERVICE).await;
+        node
+    }
+
+    #[tokio::test]
+    async fn test_split_brain_kept_by_rank() {
+        // Equal terms
+        let node = split_brain("a", Preference::Normal, 1, 1).await;
+        assert!(node.service(DEFAULT_SERVICE).alive);
+        let node = split_brain("b", Preference::Normal, 1, 1).await;
+        assert!(!node.service(DEFAULT_SERVICE).alive);
+        assert_eq!(node.membership.active_term(DEFAULT_SERVICE), None);
+
+        // Rank goes before the term
+        let node = split_brain("b", Preference::Normal, 2, 1).await;
+        assert!(!node.service(DEFAULT_SERVICE).alive);
+        let recent = node.events.recent();
+        assert!(matches!(
+            &recent[0].event,
+            Event::SplitBrain { active, kept, term: 1 } if active == &["a", "b"] && kept == "a"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_split_brain_between_drained_nodes_kept_by_term() {
+        // Both are outside the election order, so the newer term wins over the higher priority
+        let node = split_brain("a", Preference::Drained, 1, 2).await;
+        assert!(!node.service(DEFAULT_SERVICE).alive);
+        let node = split_brain("b", Preference::Drained, 2, 1).await;
+        assert!(node.service(DEFAULT_SERVICE).alive);
+        assert_eq!(node.service(DEFAULT_SERVICE).split_brain, vec!["a"]);
+        assert!(matches!(
+            &node.events.recent()[0].event,
+            Event::SplitBrain { kept, term: 2, .. } if kept == "b"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_quorum_alone() {
+        let mut node = node("a");
+        node.config.lock().unwrap().quorum = true;
+        node.elect(DEFAULT_SERVICE, 0).await;
+        assert!(!node.service(DEFAULT_SERVICE).alive);
+        assert!(!node.service(DEFAULT_SERVICE).leases.holds_lease());
+
+        // Running it when the other node becomes unreachable
+        lease_running_out(&mut node, LEASE_DURATION);
+        node.membership.set_active_term(DEFAULT_SERVICE, Some(1));
+        node.elect(DEFAULT_SERVICE, 0).await;
+        assert!(!node.service(DEFAULT_SERVICE).alive);
+        assert_eq!(node.membership.active_term(DEFAULT_SERVICE), None);
+    }
+
+    #[tokio::test]
+    async fn test_minority_cannot_acquire_the_lease() {
+        let mut node = node("a");
+        let port = stub(Duration::ZERO, |request| match request {
+            Request::Lease { term, .. } => Response::Lease {
+                granted: true,
+                term,
+                holder: Some("a".to_string()),
+            },
+            _ => Response::Pong,
+        })
+        .await;
+        {
+            let mut config = node.config.lock().unwrap();
+            config.quorum = true;
+            config.nodes[1].port = port as u32;
+            let mut c = config.nodes[1].clone();
+            c.name = "c".to_string();
+            config.nodes.push(c);
+        }
+
+        // Only we are on our side of the partition
+        assert!(!node.acquire_lease(DEFAULT_SERVICE).await);
+        assert!(!node.service(DEFAULT_SERVICE).leases.holds_lease());
+
+        // With "b", we're two of three
+        announce(&node, configured(&node, "b"), Preference::Normal, None);
+        assert!(node.acquire_lease(DEFAULT_SERVICE).await);
+        assert!(node.service(DEFAULT_SERVICE).leases.holds_lease());
+    }
+}
@@ -1,30 +1,93 @@
 use anyhow::{bail, Result};
+use futures::future::select_ok;
+use rustls::ClientConfig;
+use serde_json::Value;
 use std::{
-    io::{BufRead, BufReader},
-    sync::mpsc,
-    thread,
+    collections::{BTreeMap, HashMap},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
     time::Duration,
 };
-#[allow(unused_imports)]
-use std::{
-    io::{Read, Write},
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
     net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::Mutex as AsyncMutex,
+    time::{timeout, timeout_at, Instant},
 };
+use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use crate::{
-    config::{Config, ProviderNode},
-    debug, log,
+    auth::{self, ClusterAuth, Role, Session},
+    config::{Config, ExecutionInstructions, FailureDetection, ProviderNode},
+    debug,
+    lease::{Vote, LEASE_REQUEST_TIMEOUT},
+    log,
+    membership::{MemberUpdate, Preference},
+    node::HANDOVER_TIMEOUT,
     parser::Parser,
+    protocol::{self, Envelope, Request, Response},
+    resolver::Resolver,
     timestamp::Timestamp,
+    tls,
 };
 
+/// An outbound peer connection, optionally wrapped in TLS.
+#[derive(Debug)]
+pub enum PeerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct NodeInfo {
     pub target_name: String,
     pub target: String,
     pub port: u32,
-    stream: Option<TcpStream>,
+    stream: Option<PeerStream>,
+    next_request_id: u64,
+    session: Option<Session>,
 }
 
 impl NodeInfo {
@@ -32,106 +95,208 @@ impl NodeInfo {
         target_name: String,
         target: String,
         port: u32,
-        stream: Option<TcpStream>,
+        stream: Option<PeerStream>,
     ) -> NodeInfo {
         NodeInfo {
             target_name,
             target,
             port,
             stream,
+            next_request_id: 0,
+            session: None,
         }
     }
 
-    pub fn update_config(&mut self, config_self_mutex: Arc<Mutex<Config>>) -> Result<()> {
-        if let Some(ref mut stream) = self.stream {
-            let (tx, rx) = mpsc::channel();
-            let read_stream = stream.try_clone().unwrap();
-
-            thread::spawn(move || {
-                let mut reader = BufReader::new(read_stream);
-                let mut response = String::new();
-                match reader.read_line(&mut response) {
-                    Ok(_) => {
-                        debug!("Inside: Received response: {:?}", response);
-                        tx.send(response.trim().to_string()).unwrap_or_default();
-                    }
-                    Err(e) => {
-                        debug!("Error reading response: {:?}", e);
-                        tx.send(String::new()).unwrap_or_default();
-                    }
+    /// Proves the cluster secret to the peer and signs all further traffic.
+    pub async fn authenticate(&mut self, auth: &ClusterAuth) -> Result<()> {
+        let client_nonce = auth::nonce()?;
+        let hello = Request::Hello {
+            node: auth.node_name.clone(),
+            nonce: client_nonce.clone(),
+        };
+
+        let server_nonce = match self.request(hello, Duration::from_secs(2)).await? {
+            Response::Challenge { nonce, proof } => {
+                if !auth.verify_server_proof(&client_nonce, &nonce, &proof) {
+                    bail!("{} doesn't know the cluster secret", self.target_name);
                 }
-            });
+                nonce
+            }
+            other => bail!("Unexpected response to hello: {:?}", other),
+        };
+
+        // The answer to our proof is already signed with the session keys
+        self.session = Some(auth.session(&client_nonce, &server_nonce, Role::Client));
+        let proof = auth.client_proof(&client_nonce, &server_nonce);
+        match self
+            .request(Request::Authenticate { proof }, Duration::from_secs(2))
+            .await
+        {
+            Ok(Response::Authenticated) => Ok(()),
+            Ok(other) => bail!("Unexpected response to authenticate: {:?}", other),
+            Err(e) => Err(e),
+        }
+    }
 
-            stream.write_all(b"GET CONFIG\n")?;
+    /// Sends a request and waits for the response carrying the same id.
+    ///
+    /// Any I/O or framing error leaves the stream in an unknown state, so it is dropped and
+    /// the next caller reconnects.
+    pub async fn request(&mut self, request: Request, timeout: Duration) -> Result<Response> {
+        self.next_request_id += 1;
+        let id = self.next_request_id;
 
-            let s = match rx.recv_timeout(Duration::from_secs(2)) {
-                Ok(response) => {
-                    debug!("Received response: {:?}", response);
-                    response.replace("\\n", "\n")
-                }
-                Err(e) => {
-                    debug!("Timeout waiting for response: {:?}", e);
-                    String::new()
-                }
-            };
-            if s.is_empty() {
-                debug!("Empty response: {:?}", s);
-                bail!("No response");
+        let Some(ref mut stream) = self.stream else {
+            debug!("No stream for {}", self.target_name);
+            bail!("No stream");
+        };
+
+        let response = match exchange(
+            stream,
+            self.session.as_mut(),
+            Envelope::new(id, request),
+            timeout,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                self.stream = None;
+                return Err(e);
             }
+        };
 
-            let cfg: Config = match serde_yaml::from_str(&s) {
-                Ok(cfg) => cfg,
-                Err(e) => {
-                    debug!("Error parsing config: {:?}", e);
-                    bail!(e);
-                }
-            };
+        match response {
+            Response::Error { code, message } => {
+                bail!("{} replied with {:?}: {}", self.target_name, code, message)
+            }
+            response => Ok(response),
+        }
+    }
 
-            let mut config_self = config_self_mutex.lock().unwrap();
-            if config_self.config_metadata.last_updated > cfg.config_metadata.last_updated {
-                debug!("Local config is newer, aborting");
-                return Ok(());
+    pub async fn update_config(&mut self, config_self_mutex: Arc<Mutex<Config>>) -> Result<()> {
+        let s = match self
+            .request(Request::GetConfig, Duration::from_secs(2))
+            .await?
+        {
+            Response::Config { yaml } => yaml,
+            other => {
+                debug!("Unexpected response: {:?}", other);
+                bail!("Unexpected response");
             }
+        };
+        if s.is_empty() {
+            debug!("Empty response: {:?}", s);
+            bail!("No response");
+        }
 
-            // Update the config
-            // Execution instructions
-            config_self.execution.instructions = cfg.execution.instructions;
+        let cfg: Config = match serde_yaml::from_str(&s) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                debug!("Error parsing config: {:?}", e);
+                bail!(e);
+            }
+        };
 
-            let node_self_name = config_self.config_metadata.name.clone();
+        let mut config_self = config_self_mutex.lock().unwrap();
+        if config_self.config_metadata.last_updated > cfg.config_metadata.last_updated {
+            debug!("Local config is newer, aborting");
+            return Ok(());
+        }
 
-            // Add new Nodes (that do not exist in our config, but exist in the other config)
-            for node in &cfg.nodes {
-                if node.name == node_self_name {
-                    continue;
-                }
-                if !config_self.nodes.iter().any(|d| d.name == node.name) {
-                    config_self.nodes.push(node.clone());
-                }
+        // Update the config
+        // Services
+        let last_updated = config_self
+            .execution
+            .as_ref()
+            .map_or_else(Timestamp::now, |e| e.last_updated.clone());
+        config_self.execution = cfg.execution.map(|execution| ExecutionInstructions {
+            last_updated,
+            ..execution
+        });
+        config_self.services = cfg.services;
+        config_self.preemption = cfg.preemption;
+        config_self.tie_breaker = cfg.tie_breaker;
+
+        let node_self_name = config_self.config_metadata.name.clone();
+
+        // Add new Nodes (that do not exist in our config, but exist in the other config)
+        for node in &cfg.nodes {
+            if node.name == node_self_name {
+                continue;
+            }
+            if !config_self.nodes.iter().any(|d| d.name == node.name) {
+                config_self.nodes.push(node.clone());
             }
+        }
 
-            config_self.config_metadata.last_updated = cfg.config_metadata.last_updated.clone();
-            // Wondering if we should update the last updated
-            config_self
-                .nodes
-                .iter_mut()
-                .find(|d| d.name == node_self_name)
-                .unwrap()
-                .last_updated = Timestamp::now();
+        config_self.config_metadata.last_updated = cfg.config_metadata.last_updated.clone();
+        // Wondering if we should update the last updated
+        config_self
+            .nodes
+            .iter_mut()
+            .find(|d| d.name == node_self_name)
+            .unwrap()
+            .last_updated = Timestamp::now();
 
-            config_self.write();
-            log!("Updated config successfully");
+        config_self.write();
+        log!("Updated config successfully");
 
-            return Ok(());
+        Ok(())
+    }
+}
+
+async fn exchange(
+    stream: &mut PeerStream,
+    mut session: Option<&mut Session>,
+    request: Envelope<Request>,
+    timeout_after: Duration,
+) -> Result<Response> {
+    let id = request.id;
+    let exchange = async {
+        match session.as_deref() {
+            Some(session) => protocol::write_frame_async(stream, &session.seal(request)?).await?,
+            None => protocol::write_frame_async(stream, &request).await?,
+        }
+
+        loop {
+            let response: Envelope<Value> = protocol::read_frame_async(stream).await?;
+            if let Some(session) = session.as_deref_mut() {
+                session.open(&response)?;
+            }
+            if response.id == id {
+                return Ok(serde_json::from_value(response.body)?);
+            }
+            // A late answer to a request we already gave up on
+            debug!("Discarding response {} (waiting for {})", response.id, id);
         }
+    };
 
-        debug!("No stream for {}", self.target_name);
-        bail!("No stream");
+    match timeout(timeout_after, exchange).await {
+        Ok(response) => response,
+        Err(_) => bail!("No response within {:?}", timeout_after),
     }
 }
 
+/// A peer's answer to our gossip.
+#[derive(Debug)]
+pub struct GossipReply {
+    pub updates: Vec<MemberUpdate>,
+    pub config_version: Timestamp,
+    pub term: u64,
+    pub terms: BTreeMap<String, u64>,
+}
+
 #[derive(Clone)]
 pub struct NodeConnections {
-    connections: Vec<Arc<Mutex<NodeInfo>>>,
+    /// Shared between clones, so a connection opened by one heartbeat task is reused by the next.
+    connections: Arc<Mutex<HashMap<String, Arc<AsyncMutex<NodeInfo>>>>>,
+    tls: Option<Arc<ClientConfig>>,
+    auth: Option<ClusterAuth>,
+    local_name: Option<String>,
+    resolver: Resolver,
+    /// How long probes wait for an answer, shared by all clones
+    probe_timeout: Arc<Mutex<Duration>>,
 }
 
 impl Default for NodeConnections {
@@ -143,231 +308,500 @@ impl Default for NodeConnections {
 impl NodeConnections {
     pub fn new() -> NodeConnections {
         NodeConnections {
-            connections: vec![],
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            tls: None,
+            auth: None,
+            local_name: None,
+            resolver: Resolver::new(),
+            probe_timeout: Arc::new(Mutex::new(FailureDetection::default().probe_timeout())),
         }
     }
 
-    pub fn get_node_connection(&self, node_name: String) -> Option<Arc<Mutex<NodeInfo>>> {
-        for connection in &self.connections {
-            let conn = connection.lock().unwrap();
-            if conn.target_name == node_name && conn.stream.is_some() {
-                return Some(connection.clone());
-            }
-        }
-        None
+    /// Resolver used for outgoing connections, shared by all clones.
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
     }
 
-    pub fn get_alive_connections(&self) -> &Vec<Arc<Mutex<NodeInfo>>> {
-        &self.connections
+    /// Name we identify as when connecting to peers.
+    pub fn set_local_name(&mut self, name: String) {
+        self.local_name = Some(name);
     }
 
-    pub fn ping(&mut self, node: &ProviderNode) -> bool {
-        let target = node.ip.clone();
-        let mut connection: Option<Arc<Mutex<NodeInfo>>> =
-            self.get_node_connection(node.name.clone());
+    /// Requires every connection created from now on to pass the cluster-secret handshake.
+    pub fn set_auth(&mut self, auth: Option<ClusterAuth>) {
+        self.auth = auth;
+    }
 
-        if connection.is_none()
-            || (connection.is_some() && !is_connection_alive(connection.clone().unwrap()))
-        {
-            if connection.is_some() {
-                self.remove_node_connection(node.name.clone());
-            }
-            connection = self.create_node_connection(node);
-            if connection.is_none() {
-                return false;
-            }
-        }
+    /// Enables mutual TLS for every connection created from now on.
+    pub fn set_tls(&mut self, tls: Option<Arc<ClientConfig>>) {
+        self.tls = tls;
+    }
 
-        let connection = connection.unwrap();
-        let connection_guard = connection.lock().unwrap();
+    /// Applies to probes sent from now on, by this and every other clone.
+    pub fn set_probe_timeout(&self, probe_timeout: Duration) {
+        *self.probe_timeout.lock().unwrap() = probe_timeout;
+    }
 
-        if connection_guard.stream.is_none() {
-            return false;
-        }
+    fn probe_timeout(&self) -> Duration {
+        *self.probe_timeout.lock().unwrap()
+    }
 
-        let mut stream = connection_guard
-            .stream
-            .as_ref()
-            .unwrap()
-            .try_clone()
-            .unwrap();
-
-        let (tx, rx) = mpsc::channel();
-        let read_stream = stream.try_clone().unwrap();
-
-        thread::spawn(move || {
-            let mut reader = BufReader::new(read_stream);
-            let mut response = String::new();
-            match reader.read_line(&mut response) {
-                Ok(_) => {
-                    let is_pong = response.trim() == "PONG";
-                    tx.send(is_pong as i8).unwrap_or_default();
-                }
-                Err(e) => {
-                    debug!("Error reading response (in fn `ping`): {:?}", e);
-                    tx.send(-1).unwrap_or_default();
-                }
-            }
-        });
+    /// The connection to `node_name`, if it's open. Waits for requests in flight on it.
+    pub async fn get_node_connection(
+        &self,
+        node_name: String,
+    ) -> Option<Arc<AsyncMutex<NodeInfo>>> {
+        let connection = self.connections.lock().unwrap().get(&node_name).cloned()?;
+        let open = connection.lock().await.stream.is_some();
+        open.then_some(connection)
+    }
 
-        // Write PING
-        let _ = stream.write_all(b"PING\n");
+    pub fn get_alive_connections(&self) -> Vec<Arc<AsyncMutex<NodeInfo>>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
 
-        let _ = stream.flush();
+    /// Returns the open connection to `node`, reconnecting if it went away.
+    async fn connection_for(&self, node: &ProviderNode) -> Option<Arc<AsyncMutex<NodeInfo>>> {
+        match self.get_node_connection(node.name.clone()).await {
+            Some(connection) => Some(connection),
+            None => self.create_node_connection(node).await,
+        }
+    }
 
-        let reply = rx.recv_timeout(Duration::from_secs(2)).unwrap_or_default();
-        if reply == -1 {
-            self.remove_node_connection(target.clone());
+    pub async fn ping(&self, node: &ProviderNode) -> bool {
+        let Some(connection) = self.connection_for(node).await else {
+            return false;
+        };
+
+        let reply = connection
+            .lock()
+            .await
+            .request(Request::Ping, self.probe_timeout())
+            .await;
+
+        match reply {
+            Ok(Response::Pong) => true,
+            Ok(other) => {
+                debug!("Unexpected response to ping: {:?}", other);
+                false
+            }
+            Err(e) => {
+                debug!("Error pinging {}: {:#}", node.name, e);
+                self.remove_node_connection(node.name.clone());
+                false
+            }
         }
-        reply == 1
-    }
-
-    pub fn create_node_connection(&mut self, node: &ProviderNode) -> Option<Arc<Mutex<NodeInfo>>> {
-        let stream = TcpStream::connect_timeout(
-            &std::net::SocketAddr::new(node.ip.clone().parse().unwrap(), node.port as u16),
-            Duration::from_millis(500),
-        );
-
-        match stream {
-            Ok(stream) => {
-                let connection = Arc::new(Mutex::new(NodeInfo::new(
-                    node.name.clone(),
-                    node.ip.clone(),
-                    node.port,
-                    Some(stream),
-                )));
-
-                self.connections.push(connection.clone());
-                Some(connection.clone())
+    }
+
+    /// Probes `node` with our membership updates. Returns `None` if it didn't answer.
+    pub async fn gossip(
+        &self,
+        node: &ProviderNode,
+        updates: Vec<MemberUpdate>,
+        config_version: Timestamp,
+        term: u64,
+        terms: BTreeMap<String, u64>,
+    ) -> Option<GossipReply> {
+        let connection = self.connection_for(node).await?;
+
+        let request = Request::Gossip {
+            updates,
+            config_version,
+            term,
+            terms,
+        };
+        let reply = connection
+            .lock()
+            .await
+            .request(request, self.probe_timeout())
+            .await;
+
+        match reply {
+            Ok(Response::Gossip {
+                updates,
+                config_version,
+                term,
+                terms,
+            }) => Some(GossipReply {
+                updates,
+                config_version,
+                term,
+                terms,
+            }),
+            Ok(other) => {
+                debug!("Unexpected response to gossip: {:?}", other);
+                None
             }
+            Err(e) => {
+                debug!("Error gossiping with {}: {:#}", node.name, e);
+                self.remove_node_connection(node.name.clone());
+                None
+            }
+        }
+    }
 
-            Err(error) => {
-                if error.kind() != std::io::ErrorKind::ConnectionRefused {
-                    log!("-> Problem creating the stream: {:?}", error);
-                }
+    /// Asks `node` for the leadership lease of `service` in `term`. Returns `None` if it
+    /// didn't answer.
+    pub async fn request_lease(
+        &self,
+        node: &ProviderNode,
+        service: &str,
+        term: u64,
+    ) -> Option<Vote> {
+        let connection = self.connection_for(node).await?;
+        let request = Request::Lease {
+            term,
+            service: service.to_string(),
+        };
+        // The leases of all services are renewed at once. Waiting for the connection counts
+        // towards the timeout, so a peer that's slow to answer doesn't hold all of them up.
+        let deadline = Instant::now() + LEASE_REQUEST_TIMEOUT;
+        let Ok(mut connection) = timeout_at(deadline, connection.lock()).await else {
+            debug!(
+                "Connection to {} busy, skipping the lease request",
+                node.name
+            );
+            return None;
+        };
+        let reply = connection
+            .request(request, deadline.saturating_duration_since(Instant::now()))
+            .await;
+
+        match reply {
+            Ok(Response::Lease {
+                granted,
+                term,
+                holder,
+            }) => Some(Vote {
+                granted,
+                term,
+                holder,
+            }),
+            Ok(other) => {
+                debug!("Unexpected response to lease: {:?}", other);
+                None
+            }
+            Err(e) => {
+                debug!("Error asking {} for the lease: {:#}", node.name, e);
                 None
             }
         }
     }
 
-    pub fn remove_node_connection(&mut self, target_name: String) {
-        if let Some(pos) = self
-            .connections
-            .iter()
-            .position(|conn| conn.lock().unwrap().target_name == target_name)
-        {
-            self.connections.remove(pos);
+    /// Tells `node` we no longer hold the lease of `service` in `term`.
+    pub async fn release_lease(&self, node: &ProviderNode, service: &str, term: u64) {
+        let Some(connection) = self.get_node_connection(node.name.clone()).await else {
+            return;
+        };
+        let request = Request::Release {
+            term,
+            service: service.to_string(),
+        };
+        let reply = connection
+            .lock()
+            .await
+            .request(request, Duration::from_secs(1))
+            .await;
+        if let Err(e) = reply {
+            debug!("Error releasing the lease with {}: {:#}", node.name, e);
         }
     }
 
-    pub fn confirm(&mut self, source: &str, is_ip: bool) -> Option<String> {
-        for connection in &self.connections {
-            let conn = connection.lock().unwrap();
-            if conn.stream.is_none() {
-                continue;
+    /// Asks `node`, active for `service`, to stop it so we can take over. `None` if it didn't
+    /// answer in time.
+    pub async fn handover(
+        &self,
+        node: &ProviderNode,
+        service: &str,
+        preference: Preference,
+    ) -> Option<bool> {
+        let connection = self.connection_for(node).await?;
+        let request = Request::Handover {
+            preference,
+            service: service.to_string(),
+        };
+        let reply = connection
+            .lock()
+            .await
+            // A little longer than the peer waits for its process, so we get its answer
+            .request(request, HANDOVER_TIMEOUT + Duration::from_secs(1))
+            .await;
+
+        match reply {
+            Ok(Response::Handover { stopped }) => Some(stopped),
+            Ok(other) => {
+                debug!("Unexpected response to handover: {:?}", other);
+                None
             }
+            Err(e) => {
+                debug!("Error asking {} to hand over: {:#}", node.name, e);
+                None
+            }
+        }
+    }
 
-            let mut stream = conn.stream.as_ref().unwrap();
-            stream
-                .write_all(format!("CONFIRM:{}:{}\n", is_ip as u8, source).as_bytes())
-                .unwrap();
-
-            let reader = BufReader::new(stream);
-
-            let sis_ip = is_ip.to_string();
+    /// Pulls the config of `node_name` if we are connected to it.
+    pub async fn update_config_from(&self, node_name: &str, config: Arc<Mutex<Config>>) {
+        let Some(connection) = self.get_node_connection(node_name.to_string()).await else {
+            return;
+        };
+        let result = connection.lock().await.update_config(config).await;
+        if let Err(e) = result {
+            log!("Failed to update config from {}: {:#}", node_name, e);
+        }
+    }
 
-            for line in reader.lines() {
-                if line.is_err() {
-                    log!("Error reading line: {:?}", line.err());
-                    return None;
-                };
-                // Template: CONFIRM:_:_:bool
-                // bool is 0/1
-                let line = line.unwrap();
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() != 4 {
-                    log!("Invalid response: {}", line);
-                    return None;
+    pub async fn create_node_connection(
+        &self,
+        node: &ProviderNode,
+    ) -> Option<Arc<AsyncMutex<NodeInfo>>> {
+        let addrs = match self.resolver.resolve(&node.ip, node.port as u16).await {
+            Ok(addrs) => addrs,
+            Err(error) => {
+                debug!("-> Not connecting to \"{}\": {:#}", node.name, error);
+                return None;
+            }
+        };
+
+        // Hostnames may resolve to several addresses, e.g. both IPv6 and IPv4
+        let mut last_error = None;
+        let mut stream = None;
+        for addr in addrs {
+            match timeout(Duration::from_millis(500), TcpStream::connect(addr)).await {
+                Ok(Ok(connected)) => {
+                    stream = Some(connected);
+                    break;
                 }
+                Ok(Err(error)) => last_error = Some(error),
+                Err(_) => last_error = Some(io::Error::from(io::ErrorKind::TimedOut)),
+            }
+        }
 
-                if parts[0] != "CONFIRM" {
-                    log!("Invalid response: {}", line);
-                    return None;
+        let stream = match (stream, last_error) {
+            (Some(stream), _) => stream,
+            (None, Some(error)) => {
+                if error.kind() != io::ErrorKind::ConnectionRefused {
+                    log!("-> Problem creating the stream: {:?}", error);
                 }
-
-                if parts[1] != sis_ip {
-                    log!("Invalid response: {}", line);
+                return None;
+            }
+            (None, None) => return None,
+        };
+
+        let stream = match &self.tls {
+            Some(tls) => match tls_handshake(tls.clone(), node, stream).await {
+                Ok(stream) => PeerStream::Tls(Box::new(stream)),
+                Err(error) => {
+                    log!(
+                        "-> TLS handshake with \"{}\" failed: {:#}",
+                        node.name,
+                        error
+                    );
                     return None;
                 }
+            },
+            None => PeerStream::Plain(stream),
+        };
+
+        let mut info = NodeInfo::new(node.name.clone(), node.ip.clone(), node.port, Some(stream));
+        if let Some(auth) = &self.auth {
+            if let Err(error) = info.authenticate(auth).await {
+                log!(
+                    "-> Authentication with \"{}\" failed: {:#}",
+                    node.name,
+                    error
+                );
+                return None;
+            }
+        }
 
-                if parts[2] != source {
-                    log!("Invalid response: {}", line);
-                    return None;
+        if let Some(local_name) = &self.local_name {
+            let identify = Request::Identify {
+                node: local_name.clone(),
+            };
+            match info.request(identify, Duration::from_secs(2)).await {
+                Ok(Response::Identified { admitted: false }) => {
+                    log!("-> \"{}\" is still verifying us", node.name)
                 }
-
-                if parts[3] == "1" {
-                    return Some(conn.target_name.clone());
+                Ok(_) => (),
+                // Older peers don't know about `Identify`
+                Err(_) if info.stream.is_some() => (),
+                Err(error) => {
+                    log!("-> Identifying to \"{}\" failed: {:#}", node.name, error);
+                    return None;
                 }
             }
         }
 
-        None
+        let connection = Arc::new(AsyncMutex::new(info));
+
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(node.name.clone(), connection.clone());
+        Some(connection)
     }
 
-    pub fn get_config_for(
-        &mut self,
+    pub fn remove_node_connection(&self, target_name: String) {
+        self.connections.lock().unwrap().remove(&target_name);
+    }
+
+    /// Asks each of the `voters` whether it knows `source` (an IP if `is_ip`, else a node
+    /// name). Returns the name of the first one that vouches for it.
+    pub async fn confirm(
+        &self,
+        voters: &[ProviderNode],
         source: &str,
-        target_name: String,
-    ) -> Option<ProviderNode> {
-        for connection in &self.connections {
-            let conn = connection.lock().unwrap();
-            if conn.stream.is_none() || conn.target_name != target_name {
+        is_ip: bool,
+    ) -> Option<String> {
+        for voter in voters {
+            let Some(connection) = self.connection_for(voter).await else {
+                continue;
+            };
+            let mut conn = connection.lock().await;
+            if conn.stream.is_none() {
                 continue;
             }
 
-            let mut stream = conn.stream.as_ref().unwrap();
-            stream.write_all(b"GET CONFIG\n").unwrap();
+            let request = Request::Confirm {
+                source: source.to_string(),
+                is_ip,
+            };
+            match conn.request(request, Duration::from_secs(2)).await {
+                Ok(Response::Confirm {
+                    source: confirmed_source,
+                    is_ip: confirmed_is_ip,
+                    confirmed,
+                }) => {
+                    if confirmed_source != source || confirmed_is_ip != is_ip {
+                        log!("Invalid confirmation from {}", conn.target_name);
+                        continue;
+                    }
+                    if confirmed {
+                        return Some(conn.target_name.clone());
+                    }
+                }
+                Ok(other) => log!("Invalid response: {:?}", other),
+                Err(e) => log!("Error confirming with {}: {:#}", conn.target_name, e),
+            }
+        }
 
-            let reader = BufReader::new(stream);
+        None
+    }
 
-            for line in reader.lines() {
-                if line.is_err() {
-                    log!("Error reading line: {:?}", line.err());
-                    continue;
-                };
-                let line = line.unwrap();
-                let mut parser = Parser::new(line.as_bytes());
+    /// Asks up to `k` of the `helpers` to ping `target` for us, all at once. Returns the name
+    /// of the first helper that reached it.
+    pub async fn indirect_ping(
+        &self,
+        target: &ProviderNode,
+        helpers: &[String],
+        k: usize,
+    ) -> Option<String> {
+        let probes: Vec<_> = helpers
+            .iter()
+            .filter(|name| **name != target.name)
+            .take(k)
+            .map(|helper| Box::pin(self.ping_through(helper, target)))
+            .collect();
+        if probes.is_empty() {
+            return None;
+        }
 
-                let cfg = match parser.parse(None) {
-                    Ok(cfg) => cfg,
-                    Err(_) => {
-                        stream.write_all(b"AUTH FAIL: BAD CONFIG\n").unwrap();
-                        continue;
-                    }
-                };
+        select_ok(probes).await.ok().map(|(helper, _)| helper)
+    }
 
-                if let Some(provider) =  cfg.nodes.iter().find(|d| d.ip.clone() == source) {
-                    return Some(provider.clone());
-                } else {
-                    return None;
+    /// Asks `helper` to ping `target`. Fails unless it could.
+    async fn ping_through(&self, helper: &str, target: &ProviderNode) -> Result<String> {
+        let Some(connection) = self.get_node_connection(helper.to_string()).await else {
+            bail!("not connected to {}", helper);
+        };
+        let mut conn = connection.lock().await;
+
+        let request = Request::PingReq {
+            target: target.name.clone(),
+        };
+        // The helper may have to connect to the target first
+        match conn.request(request, 2 * self.probe_timeout()).await {
+            Ok(Response::Probe {
+                target: probed,
+                alive,
+            }) => {
+                if probed != target.name {
+                    log!("Invalid probe result from {}", conn.target_name);
+                    bail!("invalid probe result");
+                }
+                if !alive {
+                    bail!("{} couldn't reach {}", helper, target.name);
                 }
+                Ok(conn.target_name.clone())
+            }
+            Ok(other) => {
+                log!("Invalid response: {:?}", other);
+                bail!("invalid response");
+            }
+            Err(e) => {
+                debug!("Error probing through {}: {:#}", conn.target_name, e);
+                Err(e)
             }
         }
-        None
     }
-}
 
-fn is_connection_alive(connection: Arc<Mutex<NodeInfo>>) -> bool {
-    let connection_guard = connection.lock().unwrap();
-    if connection_guard.stream.is_none() {
-        return false;
+    /// Looks `source` up in the config of `target_name`, typically the peer that vouched for it.
+    pub async fn get_config_for(
+        &self,
+        source: &str,
+        is_ip: bool,
+        target_name: String,
+    ) -> Option<ProviderNode> {
+        let connection = self.get_node_connection(target_name).await?;
+        let mut conn = connection.lock().await;
+
+        let yaml = match conn
+            .request(Request::GetConfig, Duration::from_secs(2))
+            .await
+        {
+            Ok(Response::Config { yaml }) => yaml,
+            Ok(other) => {
+                log!("Invalid response: {:?}", other);
+                return None;
+            }
+            Err(e) => {
+                log!("Error getting config from {}: {:#}", conn.target_name, e);
+                return None;
+            }
+        };
+
+        let mut parser = Parser::new(yaml.as_bytes());
+        let cfg = parser.parse(None).ok()?;
+
+        cfg.nodes
+            .iter()
+            .find(|d| {
+                if is_ip {
+                    self.resolver.matches(&d.ip, source)
+                } else {
+                    d.name == source
+                }
+            })
+            .cloned()
     }
+}
 
-    let mut stream = connection_guard.stream.as_ref().unwrap();
-    match stream.write(&[]) {
-        Ok(_) => true,
-        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => true,
-        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => false,
-        Err(_) => false,
+/// Connects TLS on top of `stream`, requiring the peer certificate to be issued for the node's
+/// name.
+async fn tls_handshake(
+    config: Arc<ClientConfig>,
+    node: &ProviderNode,
+    stream: TcpStream,
+) -> Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(config);
+    match timeout(
+        Duration::from_secs(2),
+        connector.connect(tls::server_name(node)?, stream),
+    )
+    .await
+    {
+        Ok(stream) => Ok(stream?),
+        Err(_) => bail!("Timed out"),
     }
 }
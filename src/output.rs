@@ -0,0 +1,225 @@
+use crate::{config::OutputConfig, log};
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Child, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A log file that's moved aside once it gets too big or too old, keeping the last few.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    max_size: u64,
+    rotate_every: Option<Duration>,
+    keep: u32,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, config: &OutputConfig) -> Result<RotatingFile> {
+        let path = PathBuf::from(path);
+        let file = append(&path).with_context(|| format!("Couldn't open {}", path.display()))?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            opened_at: Instant::now(),
+            max_size: config.max_size_bytes,
+            rotate_every: config.rotate_every(),
+            keep: config.keep,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let too_big = self.size + line.len() as u64 > self.max_size;
+        let too_old = self
+            .rotate_every
+            .is_some_and(|every| self.opened_at.elapsed() >= every);
+        if self.size > 0 && (too_big || too_old) {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// `<file>` becomes `<file>.1`, `<file>.1` becomes `<file>.2` and so on, dropping the
+    /// oldest one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", i));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                match fs::rename(rotated(i), rotated(i + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = append(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+fn append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Where one output stream of the process goes
+#[derive(Clone, Default)]
+struct Sink {
+    file: Option<Arc<Mutex<RotatingFile>>>,
+    /// Prefix to print lines with, if they're forwarded
+    forward: Option<String>,
+}
+
+impl Sink {
+    fn captures(&self) -> bool {
+        self.file.is_some() || self.forward.is_some()
+    }
+
+    fn stdio(&self) -> Stdio {
+        if self.captures() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        }
+    }
+
+    /// Copies `stream` line by line until the process group closes it.
+    fn drain(self, stream: impl Read + Send + 'static, stderr: bool) {
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) => return,
+                    Ok(_) => (),
+                    Err(e) => {
+                        log!("Couldn't read the process output: {}", e);
+                        return;
+                    }
+                }
+                if let Some(file) = &self.file {
+                    if let Err(e) = file.lock().unwrap().write_line(&line) {
+                        log!("Couldn't write the process output: {}", e);
+                    }
+                }
+                if let Some(prefix) = &self.forward {
+                    let text = String::from_utf8_lossy(&line);
+                    let text = text.trim_end_matches(['\r', '\n']);
+                    if stderr {
+                        eprintln!("{} {}", prefix, text);
+                    } else {
+                        println!("{} {}", prefix, text);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Where the process's stdout and stderr go, set up before it's spawned so a file that can't
+/// be opened keeps it from starting.
+pub struct Output {
+    stdout: Sink,
+    stderr: Sink,
+}
+
+impl Output {
+    pub fn open(service: &str, config: &OutputConfig) -> Result<Output> {
+        let forward = config.forward_prefix(service);
+        let stdout_file = match &config.stdout {
+            Some(path) => Some(Arc::new(Mutex::new(RotatingFile::open(path, config)?))),
+            None => None,
+        };
+        let stderr_file = match &config.stderr {
+            // Both streams in one file have to share its size and rotation
+            Some(path) if config.stdout.as_ref() == Some(path) => stdout_file.clone(),
+            Some(path) => Some(Arc::new(Mutex::new(RotatingFile::open(path, config)?))),
+            None => None,
+        };
+
+        Ok(Output {
+            stdout: Sink {
+                file: stdout_file,
+                forward: forward.clone(),
+            },
+            stderr: Sink {
+                file: stderr_file,
+                forward,
+            },
+        })
+    }
+
+    pub fn stdout(&self) -> Stdio {
+        self.stdout.stdio()
+    }
+
+    pub fn stderr(&self) -> Stdio {
+        self.stderr.stdio()
+    }
+
+    /// Starts copying the output of `child`, spawned with `stdout()` and `stderr()`.
+    pub fn attach(self, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            self.stdout.drain(stdout, false);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.stderr.drain(stderr, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_keeps_the_newest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.log");
+        let config = OutputConfig {
+            max_size_bytes: 10,
+            keep: 2,
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(path.to_str().unwrap(), &config).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("out.log"), "fourth\n");
+        assert_eq!(read("out.log.1"), "third\n");
+        assert_eq!(read("out.log.2"), "second\n");
+        assert!(!dir.path().join("out.log.3").exists());
+    }
+
+    #[test]
+    fn test_forward_prefix() {
+        let mut config = OutputConfig::default();
+        assert_eq!(config.forward_prefix("worker"), None);
+
+        config.forward = true;
+        assert_eq!(config.forward_prefix("worker").unwrap(), "[worker]");
+        config.prefix = Some("api |".to_string());
+        assert_eq!(config.forward_prefix("worker").unwrap(), "api |");
+    }
+}
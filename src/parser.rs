@@ -22,7 +22,11 @@ impl<R: Read> Parser<R> {
         self.src.read_to_string(&mut contents)?;
 
         // parse
-        let cfg: Config = serde_yaml::from_str(&contents).unwrap();
+        let cfg: Config = serde_yaml::from_str(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        cfg.validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
         if let Some(config_str) = config_str {
             *config_str.lock().unwrap() = contents;
         }
@@ -34,6 +38,8 @@ impl<R: Read> Parser<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{NodeRole, PreemptionMode, TieBreaker};
+    use chrono::NaiveTime;
     use std::io::Cursor;
 
     #[test]
@@ -63,4 +69,254 @@ execution:
         assert_eq!(config.nodes[0].name, "test");
         assert_eq!(config.nodes[0].name, config.config_metadata.name);
     }
+
+    #[test]
+    fn test_witness_role() {
+        let yaml = r#"
+nodes:
+- name: pi
+  ip: 127.0.0.1
+  port: 8080
+  priority: 0
+  role: witness
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: pc
+  ip: 127.0.0.1
+  port: 8081
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: pi
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+"#;
+        let config = Parser::new(Cursor::new(yaml)).parse(None).unwrap();
+        assert_eq!(config.nodes[0].role, NodeRole::Witness);
+        // Nodes are providers unless they say otherwise
+        assert_eq!(config.nodes[1].role, NodeRole::Provider);
+        assert!(serde_yaml::to_string(&config)
+            .unwrap()
+            .contains("role: witness"));
+    }
+
+    #[test]
+    fn test_preemption_window() {
+        let yaml = r#"
+nodes:
+- name: pc
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: pc
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+preemption:
+  mode: delayed
+  window: 23:30-01:00
+"#;
+        let config = Parser::new(Cursor::new(yaml)).parse(None).unwrap();
+        assert_eq!(config.preemption.mode, PreemptionMode::Delayed);
+        let window = config.preemption.window.unwrap();
+        let time = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        assert!(window.contains(time("23:45")));
+        assert!(window.contains(time("00:30")));
+        assert!(!window.contains(time("01:00")));
+        assert!(!window.contains(time("12:00")));
+
+        let invalid = yaml.replace("23:30-01:00", "tonight");
+        assert!(Parser::new(Cursor::new(invalid)).parse(None).is_err());
+    }
+
+    #[test]
+    fn test_priority_ties() {
+        let yaml = r#"
+nodes:
+- name: a
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  id: 2
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: b
+  ip: 127.0.0.1
+  port: 8081
+  priority: 100
+  id: 1
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: c
+  ip: 127.0.0.1
+  port: 8082
+  priority: 50
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: pi
+  ip: 127.0.0.1
+  port: 8083
+  priority: 50
+  role: witness
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+tie_breaker: id
+"#;
+        let config = Parser::new(Cursor::new(yaml)).parse(None).unwrap();
+        assert_eq!(config.tie_breaker, TieBreaker::Id);
+        assert_eq!(config.nodes[1].id, Some(1));
+        // Witnesses never run the process, so they don't tie
+        let default = config.service("default").unwrap();
+        assert_eq!(config.priority_ties(&default), vec![vec!["a", "b"]]);
+    }
+
+    #[test]
+    fn test_execution_forms() {
+        let yaml = r#"
+nodes: []
+config_metadata:
+  name: pc
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./server  --name "my  server" 'a b'
+  last_updated: 2024-03-20 00:00:00 UTC
+"#;
+        let mut config = Parser::new(Cursor::new(yaml)).parse(None).unwrap();
+        let execution = config.execution.as_mut().unwrap();
+        assert_eq!(
+            execution.command().unwrap(),
+            vec!["./server", "--name", "my  server", "a b"]
+        );
+
+        execution.shell = true;
+        assert_eq!(execution.command().unwrap()[..2], ["sh", "-c"]);
+
+        execution.argv = vec!["./server".to_string()];
+        assert!(execution.command().is_err());
+        execution.shell = false;
+        execution.instructions = String::new();
+        assert_eq!(execution.command().unwrap(), vec!["./server"]);
+
+        execution.argv = vec![];
+        execution.instructions = "echo 'oops".to_string();
+        assert!(execution.command().is_err());
+
+        // Only a comment, nothing to run
+        let commented = yaml.replace(
+            r#"instructions: ./server  --name "my  server" 'a b'"#,
+            "instructions: \"# ./server\"",
+        );
+        assert_ne!(commented, yaml);
+        let error = Parser::new(Cursor::new(commented)).parse(None).unwrap_err();
+        assert!(error.to_string().contains("no command"));
+    }
+
+    #[test]
+    fn test_services() {
+        let yaml = r#"
+nodes:
+- name: a
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: b
+  ip: 127.0.0.1
+  port: 8081
+  priority: 50
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./api
+  last_updated: 2024-03-20 00:00:00 UTC
+services:
+- name: worker
+  execution:
+    instructions: ./worker
+    last_updated: 2024-03-20 00:00:00 UTC
+  priorities:
+    b: 100
+"#;
+        let config = Parser::new(Cursor::new(yaml)).parse(None).unwrap();
+        let services = config.services();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].name, "default");
+        assert_eq!(services[0].priority(&config.nodes[0]), Some(100));
+        // Only the listed nodes may run the worker
+        assert_eq!(services[1].priority(&config.nodes[0]), None);
+        assert_eq!(services[1].priority(&config.nodes[1]), Some(100));
+
+        // An invalid config doesn't replace the one in use
+        let config_str = Arc::new(Mutex::new(yaml.to_string()));
+        let duplicate = yaml.replace("- name: worker", "- name: default");
+        assert!(Parser::new(Cursor::new(duplicate))
+            .parse(Some(config_str.clone()))
+            .is_err());
+        assert_eq!(*config_str.lock().unwrap(), yaml);
+    }
+
+    #[test]
+    fn test_lease_outlasts_heartbeats() {
+        let yaml = |probe_timeout_ms: u64| {
+            format!(
+                r#"
+nodes:
+- name: a
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+failure_detection:
+  probe_timeout_ms: {probe_timeout_ms}
+"#
+            )
+        };
+        assert!(Parser::new(Cursor::new(yaml(2500))).parse(None).is_ok());
+        // A heartbeat could then take longer than the lease lasts
+        let error = Parser::new(Cursor::new(yaml(3000)))
+            .parse(None)
+            .unwrap_err();
+        assert!(error.to_string().contains("11000ms"));
+    }
+
+    #[test]
+    fn test_many_services_fit_within_a_lease() {
+        let mut yaml = r#"
+nodes:
+- name: a
+  ip: 127.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./api
+  last_updated: 2024-03-20 00:00:00 UTC
+services:
+"#
+        .to_string();
+        for name in ["worker", "mailer", "cron"] {
+            yaml += &format!(
+                "- name: {name}\n  execution:\n    instructions: ./{name}\n    last_updated: 2024-03-20 00:00:00 UTC\n"
+            );
+        }
+        let config = Parser::new(Cursor::new(yaml)).parse(None).unwrap();
+        assert_eq!(config.services().len(), 4);
+    }
 }
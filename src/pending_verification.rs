@@ -1,6 +1,432 @@
+use crate::{
+    config::{Config, ProviderNode},
+    log,
+    node_connections::NodeConnections,
+    resolver::Resolver,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a verification (or its rejection) is remembered before the peer may be retried.
+pub const VERIFICATION_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerificationState {
+    /// Waiting for a trusted peer to vouch
+    Pending,
+    /// Nobody vouched; further requests are refused until the entry expires
+    Rejected,
+}
+
+/// A peer that connected from an unknown address or claimed a name we don't know.
+#[derive(Debug, Clone)]
 pub struct PendingVerification {
+    /// Node name or IP the peer is verified by
     pub source: String,
     pub remote_addr: String,
-    pub redirect_node: String,
+    /// Trusted peer that vouched for `source`, once one did
+    pub redirect_node: Option<String>,
     pub is_ip: bool,
+    pub state: VerificationState,
+    pub started: Instant,
+}
+
+impl PendingVerification {
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed() > VERIFICATION_TTL
+    }
+}
+
+/// Verifications currently in flight, shared between the listener and the heartbeat.
+#[derive(Clone, Default)]
+pub struct PendingVerifications {
+    entries: Arc<Mutex<Vec<PendingVerification>>>,
+}
+
+impl PendingVerifications {
+    pub fn new() -> PendingVerifications {
+        PendingVerifications::default()
+    }
+
+    /// Registers `source` for verification. Returns `false` if it's already known, in which
+    /// case no new verification should be started.
+    pub fn start(&self, source: &str, remote_addr: &str, is_ip: bool) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| !entry.is_expired());
+        if entries.iter().any(|entry| entry.source == source) {
+            return false;
+        }
+
+        entries.push(PendingVerification {
+            source: source.to_string(),
+            remote_addr: remote_addr.to_string(),
+            redirect_node: None,
+            is_ip,
+            state: VerificationState::Pending,
+            started: Instant::now(),
+        });
+        true
+    }
+
+    pub fn vouched_by(&self, source: &str, redirect_node: &str) {
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|entry| entry.source == source)
+        {
+            entry.redirect_node = Some(redirect_node.to_string());
+        }
+    }
+
+    /// The peer was admitted; forget about it.
+    pub fn confirm(&self, source: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.source != source);
+    }
+
+    pub fn reject(&self, source: &str) {
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|entry| entry.source == source)
+        {
+            entry.state = VerificationState::Rejected;
+        }
+    }
+
+    /// Drops expired entries and returns the rest.
+    pub fn list(&self) -> Vec<PendingVerification> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| !entry.is_expired());
+        entries.clone()
+    }
+}
+
+/// What we know about a connecting peer.
+#[derive(Debug, PartialEq)]
+pub enum Trust {
+    /// A configured node, connecting from its configured address
+    Trusted(String),
+    /// Has to be vouched for by another node, by name (`is_ip == false`) or by address
+    Unverified { source: String, is_ip: bool },
+}
+
+/// Decides whether a peer connecting from `remote_addr`, optionally claiming to be `claimed`,
+/// is one of our configured nodes.
+pub fn classify(
+    config: &Config,
+    resolver: &Resolver,
+    remote_addr: &str,
+    claimed: Option<&str>,
+) -> Trust {
+    match claimed {
+        Some(name) => match config.nodes.iter().find(|node| node.name == name) {
+            Some(node) if resolver.matches(&node.ip, remote_addr) => {
+                Trust::Trusted(node.name.clone())
+            }
+            _ => Trust::Unverified {
+                source: name.to_string(),
+                is_ip: false,
+            },
+        },
+        None => match config
+            .nodes
+            .iter()
+            .find(|node| resolver.matches(&node.ip, remote_addr))
+        {
+            Some(node) => Trust::Trusted(node.name.clone()),
+            None => Trust::Unverified {
+                source: remote_addr.to_string(),
+                is_ip: true,
+            },
+        },
+    }
+}
+
+/// Asks our peers to vouch for `source` and, if one does, admits the node as described in
+/// that peer's config.
+pub async fn verify(
+    pending: PendingVerifications,
+    node_connections: NodeConnections,
+    config: Arc<Mutex<Config>>,
+    source: String,
+    remote_addr: String,
+    is_ip: bool,
+) {
+    // Only configured nodes may vouch, never members we merely heard of or the peer itself
+    let voters: Vec<ProviderNode> = {
+        let config = config.lock().unwrap();
+        config
+            .nodes
+            .iter()
+            .filter(|node| node.name != config.config_metadata.name && node.name != source)
+            .cloned()
+            .collect()
+    };
+    let Some(voucher) = node_connections.confirm(&voters, &source, is_ip).await else {
+        log!(
+            "-> Nobody vouched for {} ({}), rejecting",
+            source,
+            remote_addr
+        );
+        pending.reject(&source);
+        return;
+    };
+    pending.vouched_by(&source, &voucher);
+
+    let provider = node_connections
+        .get_config_for(&source, is_ip, voucher.clone())
+        .await;
+    let resolver = node_connections.resolver();
+    if let Some(provider) = &provider {
+        // Look hostnames up, so the address can be matched below
+        let _ = resolver.resolve(&provider.ip, provider.port as u16).await;
+    }
+    let provider = match provider {
+        Some(provider) if resolver.matches(&provider.ip, &remote_addr) => provider,
+        Some(provider) => {
+            log!(
+                "-> \"{}\" knows {} at {}, but it connected from {}, rejecting",
+                voucher,
+                source,
+                provider.ip,
+                remote_addr
+            );
+            pending.reject(&source);
+            return;
+        }
+        None => {
+            log!("-> Couldn't get {}'s entry from \"{}\"", source, voucher);
+            pending.reject(&source);
+            return;
+        }
+    };
+
+    {
+        let mut config = config.lock().unwrap();
+        let node_name = provider.name.clone();
+        match config.nodes.iter_mut().find(|node| node.name == node_name) {
+            Some(node) => *node = provider,
+            None => config.nodes.push(provider),
+        }
+        config.write();
+        log!(
+            "-> Admitted \"{}\" ({}), vouched for by \"{}\"",
+            node_name,
+            remote_addr,
+            voucher
+        );
+    }
+    pending.confirm(&source);
+}
+
+/// Nodes we heard about through gossip only join `nodes` once a trusted peer vouches for them
+/// at the address they were gossiped with. Starts a verification for each one we don't know.
+pub fn verify_joined(
+    pending: &PendingVerifications,
+    node_connections: &NodeConnections,
+    config: &Arc<Mutex<Config>>,
+    joined: Vec<ProviderNode>,
+) {
+    for node in joined {
+        let known = config
+            .lock()
+            .unwrap()
+            .nodes
+            .iter()
+            .any(|d| d.name == node.name);
+        if known || !pending.start(&node.name, &node.ip, false) {
+            continue;
+        }
+
+        log!(
+            "-> Heard about \"{}\" through gossip, verifying it with other nodes",
+            node.name
+        );
+        tokio::spawn(verify(
+            pending.clone(),
+            node_connections.clone(),
+            config.clone(),
+            node.name,
+            node.ip,
+            false,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parser::Parser,
+        protocol::{self, Envelope, Request, Response},
+        timestamp::Timestamp,
+    };
+    use tokio::net::TcpListener;
+
+    fn config() -> Config {
+        let yaml = r#"
+nodes:
+- name: pc
+  ip: 10.0.0.1
+  port: 8080
+  priority: 100
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: pc
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: ./test.sh
+  last_updated: 2024-03-20 00:00:00 UTC
+"#;
+        Parser::new(yaml.as_bytes()).parse(None).unwrap()
+    }
+
+    #[test]
+    fn test_classify() {
+        let config = config();
+        let resolver = Resolver::new();
+        assert_eq!(
+            classify(&config, &resolver, "10.0.0.1", None),
+            Trust::Trusted("pc".to_string())
+        );
+        assert_eq!(
+            classify(&config, &resolver, "10.0.0.1", Some("pc")),
+            Trust::Trusted("pc".to_string())
+        );
+        assert_eq!(
+            classify(&config, &resolver, "10.0.0.9", Some("pc")),
+            Trust::Unverified {
+                source: "pc".to_string(),
+                is_ip: false
+            }
+        );
+        assert_eq!(
+            classify(&config, &resolver, "10.0.0.9", None),
+            Trust::Unverified {
+                source: "10.0.0.9".to_string(),
+                is_ip: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_start_is_deduplicated() {
+        let pending = PendingVerifications::new();
+        assert!(pending.start("10.0.0.5", "10.0.0.5", true));
+        assert!(!pending.start("10.0.0.5", "10.0.0.5", true));
+
+        pending.reject("10.0.0.5");
+        assert!(!pending.start("10.0.0.5", "10.0.0.5", true));
+        assert_eq!(pending.list()[0].state, VerificationState::Rejected);
+
+        pending.confirm("10.0.0.5");
+        assert!(pending.list().is_empty());
+    }
+
+    fn liar_config(port: u16) -> String {
+        format!(
+            r#"
+nodes:
+- name: mallory
+  ip: 127.0.0.1
+  port: {port}
+  priority: 1000
+  last_updated: 2024-03-20 00:00:00 UTC
+- name: eve
+  ip: 127.0.0.1
+  port: {port}
+  priority: 1000
+  last_updated: 2024-03-20 00:00:00 UTC
+config_metadata:
+  name: mallory
+  last_updated: 2024-03-20 00:00:00 UTC
+"#
+        )
+    }
+
+    /// A peer that vouches for anyone, and describes every node as living on 127.0.0.1
+    async fn liar() -> ProviderNode {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    while let Ok(request) =
+                        protocol::read_frame_async::<_, Envelope<Request>>(&mut stream).await
+                    {
+                        let response = match request.body {
+                            Request::Confirm { source, is_ip } => Response::Confirm {
+                                source,
+                                is_ip,
+                                confirmed: true,
+                            },
+                            Request::GetConfig => Response::Config {
+                                yaml: liar_config(port),
+                            },
+                            _ => Response::Pong,
+                        };
+                        let envelope = Envelope::new(request.id, response);
+                        if protocol::write_frame_async(&mut stream, &envelope)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        ProviderNode {
+            name: "mallory".to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: port as u32,
+            priority: 1000,
+            last_updated: Timestamp::now(),
+            role: Default::default(),
+            id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unadmitted_member_cannot_vouch() {
+        let config = Arc::new(Mutex::new(config()));
+        let node_connections = NodeConnections::new();
+        let pending = PendingVerifications::new();
+
+        // Connected to, e.g. after hearing about it through gossip, but never admitted
+        let mallory = liar().await;
+        assert!(node_connections
+            .create_node_connection(&mallory)
+            .await
+            .is_some());
+
+        for source in ["mallory", "eve"] {
+            assert!(pending.start(source, "127.0.0.1", false));
+            verify(
+                pending.clone(),
+                node_connections.clone(),
+                config.clone(),
+                source.to_string(),
+                "127.0.0.1".to_string(),
+                false,
+            )
+            .await;
+        }
+
+        assert_eq!(config.lock().unwrap().nodes.len(), 1);
+        assert!(pending
+            .list()
+            .iter()
+            .all(|entry| entry.state == VerificationState::Rejected));
+    }
 }
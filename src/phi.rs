@@ -0,0 +1,146 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Inter-arrival times kept per peer.
+pub const WINDOW: usize = 100;
+/// Intervals needed before phi means anything.
+pub const MIN_SAMPLES: usize = 3;
+/// Floor for the standard deviation, so a very regular peer isn't suspected over a little
+/// jitter.
+pub const MIN_STD_DEVIATION: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct History {
+    last: Option<Instant>,
+    intervals: VecDeque<f64>,
+}
+
+impl History {
+    fn record(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            if self.intervals.len() == WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals
+                .push_back(now.duration_since(last).as_secs_f64() * 1000.0);
+        }
+        self.last = Some(now);
+    }
+
+    fn phi(&self, now: Instant) -> Option<f64> {
+        let last = self.last?;
+        if self.intervals.len() < MIN_SAMPLES {
+            return None;
+        }
+
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|interval| (interval - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_deviation = variance
+            .sqrt()
+            .max(MIN_STD_DEVIATION.as_secs_f64() * 1000.0);
+
+        let elapsed = now.duration_since(last).as_secs_f64() * 1000.0;
+        Some(phi(elapsed, mean, std_deviation))
+    }
+}
+
+/// -log10 of the probability that a heartbeat arrives later than `elapsed`, using a logistic
+/// approximation of the normal distribution (as in Akka).
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+/// Phi-accrual failure detector: instead of a fixed timeout, rates how suspicious a peer's
+/// silence is given how regularly we heard from it so far. Shared between the heartbeat and
+/// the listener.
+#[derive(Clone, Default)]
+pub struct PhiAccrual {
+    peers: Arc<Mutex<HashMap<String, History>>>,
+}
+
+impl PhiAccrual {
+    pub fn new() -> PhiAccrual {
+        PhiAccrual::default()
+    }
+
+    /// We heard from `name` directly.
+    pub fn heartbeat(&self, name: &str) {
+        self.heartbeat_at(name, Instant::now());
+    }
+
+    fn heartbeat_at(&self, name: &str, now: Instant) {
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .record(now);
+    }
+
+    /// Suspicion level of `name`, `None` until we've heard from it a few times. 1 means a
+    /// 10% chance of being wrong when calling it dead, 2 means 1%, and so on.
+    pub fn phi(&self, name: &str) -> Option<f64> {
+        self.phi_at(name, Instant::now())
+    }
+
+    fn phi_at(&self, name: &str, now: Instant) -> Option<f64> {
+        self.peers.lock().unwrap().get(name)?.phi(now)
+    }
+
+    /// Starts over for `name`, e.g. once it's dead, so the downtime doesn't skew its history.
+    pub fn forget(&self, name: &str) {
+        self.peers.lock().unwrap().remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let detector = PhiAccrual::new();
+        let start = Instant::now();
+        for i in 0..10 {
+            detector.heartbeat_at("b", start + Duration::from_secs(i));
+        }
+        assert_eq!(detector.phi_at("c", start), None);
+
+        let last = start + Duration::from_secs(9);
+        let on_time = detector.phi_at("b", last + Duration::from_secs(1)).unwrap();
+        let late = detector.phi_at("b", last + Duration::from_secs(3)).unwrap();
+        let silent = detector
+            .phi_at("b", last + Duration::from_secs(10))
+            .unwrap();
+        assert!(on_time < 1.0, "{}", on_time);
+        assert!(on_time < late && late < silent);
+        assert!(silent > 8.0, "{}", silent);
+    }
+
+    #[test]
+    fn test_needs_a_few_samples() {
+        let detector = PhiAccrual::new();
+        let start = Instant::now();
+        detector.heartbeat_at("b", start);
+        detector.heartbeat_at("b", start + Duration::from_secs(1));
+        assert_eq!(detector.phi_at("b", start + Duration::from_secs(60)), None);
+
+        detector.forget("b");
+        assert_eq!(detector.phi_at("b", start), None);
+    }
+}
@@ -1,22 +1,327 @@
-use crate::{config::Config, log};
+use crate::{
+    config::{ExecutionInstructions, StopPolicy},
+    debug, log,
+    output::Output,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    os::unix::process::CommandExt,
+    process::ExitStatus,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the process group gets to disappear after `SIGKILL`.
+pub const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Pids of the processes started through `Process`, which only their `Child` may reap.
+static STARTED: Mutex<BTreeSet<libc::pid_t>> = Mutex::new(BTreeSet::new());
 
 pub struct Process {
     pub child: std::process::Child,
 }
 
 impl Process {
-    pub fn new(cfg: &Config) -> Process {
-        let args: Vec<&str> = cfg.execution.instructions.split(" ").collect();
-        let child = std::process::Command::new(args[0])
+    pub fn new(service: &str, execution: &ExecutionInstructions) -> Result<Process> {
+        let args = execution.command().map_err(anyhow::Error::msg)?;
+        let mut command = std::process::Command::new(&args[0]);
+        command
             .args(&args[1..])
+            // Its own process group, so stopping it reaches whatever it started too
+            .process_group(0);
+        if let Some(path) = &execution.env_file {
+            command.envs(read_env_file(path)?);
+        }
+        command.envs(&execution.env);
+        if let Some(dir) = &execution.working_directory {
+            command.current_dir(dir);
+        }
+        let output = Output::open(service, &execution.output)?;
+        command.stdout(output.stdout()).stderr(output.stderr());
+        let mut child = command
             .spawn()
-            .expect("Couldn't spawn the process.");
+            .with_context(|| format!("Couldn't spawn \"{}\"", args[0]))?;
+        output.attach(&mut child);
+        STARTED.lock().unwrap().insert(child.id() as libc::pid_t);
+
+        Ok(Process { child })
+    }
+
+    /// Reaps the process if it exited.
+    pub fn try_wait(&mut self) -> Option<ExitStatus> {
+        match self.child.try_wait() {
+            Ok(status) => status,
+            Err(e) => {
+                log!("Couldn't check on process {}: {}", self.child.id(), e);
+                None
+            }
+        }
+    }
+
+    /// Stops the process and everything it started: `policy.signal` to its process group,
+    /// then `SIGKILL` once the grace period is over. Fails if some of the group is still
+    /// around after that.
+    pub fn stop(&mut self, policy: &StopPolicy) -> Result<()> {
+        let pgid = self.child.id();
+        log!("Stopping process group {} with {}", pgid, policy.signal);
+        self.signal_group(policy.signal.0);
+        if self.wait_for_group(policy.grace_period()) {
+            return Ok(());
+        }
+
+        log!(
+            "Process group {} still running after {}ms, killing it",
+            pgid,
+            policy.grace_period_ms
+        );
+        self.signal_group(libc::SIGKILL);
+        if self.wait_for_group(KILL_TIMEOUT) {
+            return Ok(());
+        }
+        bail!("process group {} still running after SIGKILL", pgid)
+    }
+
+    /// Kills whatever the process left behind after exiting on its own.
+    pub fn kill_leftovers(&mut self) {
+        if self.group_exists() {
+            log!("Killing what's left of process group {}", self.child.id());
+            self.signal_group(libc::SIGKILL);
+        }
+    }
+
+    /// Sends `SIGKILL` to the process group without waiting for it to go away.
+    pub fn kill(&self) {
+        self.signal_group(libc::SIGKILL);
+    }
+
+    fn signal_group(&self, signal: i32) {
+        // SAFETY: killpg has no memory safety requirements
+        unsafe { libc::killpg(self.child.id() as libc::pid_t, signal) };
+    }
+
+    /// Reaps what exited of the group, and checks whether anything is left.
+    fn group_exists(&mut self) -> bool {
+        let pgid = self.child.id() as libc::pid_t;
+        // Orphans of the group are ours to reap, see `become_subreaper`. The process itself is
+        // reaped through `child`, which couldn't tell how it exited otherwise.
+        while let Some(pid) = exited(libc::P_PGID, pgid as libc::id_t) {
+            if pid != pgid {
+                reap(pid);
+            } else if self.try_wait().is_none() {
+                break;
+            }
+        }
+        // SAFETY: killpg with signal 0 only checks whether the group exists
+        unsafe {
+            libc::killpg(pgid, 0) == 0
+                || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+        }
+    }
+
+    fn wait_for_group(&mut self, limit: Duration) -> bool {
+        let deadline = Instant::now() + limit;
+        while self.group_exists() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        true
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // If it's still around, it's left to `reap_orphans`
+        STARTED
+            .lock()
+            .unwrap()
+            .remove(&(self.child.id() as libc::pid_t));
+    }
+}
 
-        Process { child }
+/// Reaps the orphans we adopted (see `become_subreaper`), wherever they came from, e.g.
+/// daemons that left the process group. Processes we started are left to their `Process`.
+/// Meant to be called every heartbeat.
+pub fn reap_orphans() {
+    while let Some(pid) = exited(libc::P_ALL, 0) {
+        if STARTED.lock().unwrap().contains(&pid) {
+            // Its `Process` reaps it on the next check
+            return;
+        }
+        debug!("Reaping orphan {}", pid);
+        reap(pid);
     }
+}
+
+/// An exited child matching `idtype` and `id`, without reaping it.
+fn exited(idtype: libc::idtype_t, id: libc::id_t) -> Option<libc::pid_t> {
+    // SAFETY: waitid fills in the zeroed siginfo_t, and WNOWAIT leaves the child waitable
+    unsafe {
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if libc::waitid(idtype, id, &mut info, flags) != 0 {
+            return None;
+        }
+        Some(info.si_pid()).filter(|&pid| pid > 0)
+    }
+}
+
+fn reap(pid: libc::pid_t) {
+    // SAFETY: waitpid without a status pointer has no memory safety requirements
+    unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) };
+}
+
+/// Reads `KEY=VALUE` lines. Blank lines, `#` comments and `export` prefixes are skipped,
+/// quotes around values removed.
+fn read_env_file(path: &str) -> Result<Vec<(String, String)>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Couldn't read env file {}", path))?;
+
+    let mut vars = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("{}:{}: expected KEY=VALUE", path, i + 1);
+        };
+        let value = value.trim();
+        let unquoted = ['"', '\'']
+            .iter()
+            .find_map(|&quote| value.strip_prefix(quote)?.strip_suffix(quote));
+        vars.push((
+            key.trim().to_string(),
+            unquoted.unwrap_or(value).to_string(),
+        ));
+    }
+    Ok(vars)
+}
+
+/// Makes processes orphaned by the process (e.g. daemonized grandchildren) our children
+/// instead of init's, so we can reap them and tell when its group is really gone. Init doesn't
+/// always reap orphans in containers. Called once at startup.
+#[cfg(target_os = "linux")]
+pub fn become_subreaper() {
+    // SAFETY: PR_SET_CHILD_SUBREAPER only sets a flag on the calling process
+    unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn become_subreaper() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Signal, DEFAULT_SERVICE};
+
+    #[test]
+    fn test_stop_escalates_to_the_whole_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("stubborn.sh");
+        let pid_file = dir.path().join("grandchild.pid");
+        std::fs::write(
+            &script,
+            format!(
+                "trap '' TERM\nsleep 100 &\necho $! > {}\nwait\n",
+                pid_file.display()
+            ),
+        )
+        .unwrap();
+
+        let yaml = format!(
+            r#"
+nodes: []
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: sh {}
+  last_updated: 2024-03-20 00:00:00 UTC
+"#,
+            script.display()
+        );
+        let cfg: Config = serde_yaml::from_str(&yaml).unwrap();
+        become_subreaper();
+        let mut process = Process::new(DEFAULT_SERVICE, cfg.execution.as_ref().unwrap()).unwrap();
+        while std::fs::read_to_string(&pid_file).map_or(true, |pid| !pid.ends_with('\n')) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let grandchild: libc::pid_t = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+
+        let policy = StopPolicy {
+            signal: Signal(libc::SIGTERM),
+            grace_period_ms: 200,
+        };
+        let started = Instant::now();
+        process.stop(&policy).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        // SAFETY: signal 0 only checks whether the process exists
+        assert_eq!(unsafe { libc::kill(grandchild, 0) }, -1);
+    }
+
+    #[test]
+    fn test_environment_and_working_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("app.env"),
+            "# comment\nexport A=\"from file\"\nB=overridden\n",
+        )
+        .unwrap();
+
+        let yaml = format!(
+            r#"
+nodes: []
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  argv: [sh, -c, 'echo "$A, $B" > out']
+  env:
+    B: from config
+  env_file: {}/app.env
+  working_directory: {}
+  last_updated: 2024-03-20 00:00:00 UTC
+"#,
+            dir.path().display(),
+            dir.path().display()
+        );
+        let cfg: Config = serde_yaml::from_str(&yaml).unwrap();
+        let mut process = Process::new(DEFAULT_SERVICE, cfg.execution.as_ref().unwrap()).unwrap();
+        process.child.wait().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("out")).unwrap(),
+            "from file, from config\n"
+        );
+    }
+
+    #[test]
+    fn test_exit_status_survives_cleanup() {
+        let yaml = r#"
+nodes: []
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  argv: [sh, -c, 'exit 3']
+  last_updated: 2024-03-20 00:00:00 UTC
+"#;
+        let cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let mut process = Process::new(DEFAULT_SERVICE, cfg.execution.as_ref().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(200));
 
-    pub fn kill(&mut self) {
-        log!("Killing process {}", self.child.id());
-        self.child.kill().expect("!kill");
+        // Looking for leftovers doesn't reap the process behind `child`'s back
+        process.kill_leftovers();
+        assert_eq!(process.try_wait().and_then(|s| s.code()), Some(3));
     }
 }
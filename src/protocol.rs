@@ -0,0 +1,324 @@
+use crate::{
+    config::{default_service, is_default_service},
+    membership::{MemberUpdate, Preference},
+    timestamp::Timestamp,
+};
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version spoken by this node. Bump it whenever a message changes shape.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest version we still understand.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Upper bound for a single frame, so a bogus length can't make us allocate gigabytes.
+pub const MAX_FRAME_LEN: u32 = 8 * 1024 * 1024;
+
+/// Wire format: a 4-byte big-endian length followed by a JSON encoded envelope.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Envelope<T> {
+    pub version: u16,
+    pub id: u64,
+    pub body: T,
+    /// HMAC of the envelope, present once a cluster-secret session is established.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(id: u64, body: T) -> Envelope<T> {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            id,
+            body,
+            mac: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Ping,
+    GetConfig,
+    Confirm {
+        source: String,
+        is_ip: bool,
+    },
+    /// Starts the cluster-secret handshake.
+    Hello {
+        node: String,
+        nonce: String,
+    },
+    /// Proves knowledge of the cluster secret in answer to a `Challenge`.
+    Authenticate {
+        proof: String,
+    },
+    /// Tells the listener which node is connecting.
+    Identify {
+        node: String,
+    },
+    /// Asks the listener to ping `target` on our behalf.
+    PingReq {
+        target: String,
+    },
+    /// A probe carrying membership updates, the sender's config version and election terms:
+    /// `term` for the `default` service, `terms` for the others.
+    Gossip {
+        updates: Vec<MemberUpdate>,
+        config_version: Timestamp,
+        #[serde(default)]
+        term: u64,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        terms: BTreeMap<String, u64>,
+    },
+    /// Asks for (or renews) the leadership lease of `service` for `term`.
+    Lease {
+        term: u64,
+        #[serde(
+            default = "default_service",
+            skip_serializing_if = "is_default_service"
+        )]
+        service: String,
+    },
+    /// The sender stepped down and no longer needs the lease of `service` it got for `term`.
+    Release {
+        term: u64,
+        #[serde(
+            default = "default_service",
+            skip_serializing_if = "is_default_service"
+        )]
+        service: String,
+    },
+    /// Asks the node running `service` to stop it, so the sender can take over. Carries the
+    /// sender's preference, which may not have been gossiped yet.
+    Handover {
+        #[serde(default)]
+        preference: Preference,
+        #[serde(
+            default = "default_service",
+            skip_serializing_if = "is_default_service"
+        )]
+        service: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Pong,
+    /// The YAML config of the responding node, without local-only settings.
+    Config {
+        yaml: String,
+    },
+    Confirm {
+        source: String,
+        is_ip: bool,
+        confirmed: bool,
+    },
+    /// Server nonce plus proof that the server knows the cluster secret.
+    Challenge {
+        nonce: String,
+        proof: String,
+    },
+    Authenticated,
+    /// Answer to `Gossip`, with the listener's own updates, config version and terms.
+    Gossip {
+        updates: Vec<MemberUpdate>,
+        config_version: Timestamp,
+        #[serde(default)]
+        term: u64,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        terms: BTreeMap<String, u64>,
+    },
+    /// Answer to `Lease`. `term` is the listener's current term, `holder` who it voted for.
+    Lease {
+        granted: bool,
+        term: u64,
+        holder: Option<String>,
+    },
+    Released,
+    /// Answer to `Handover`, sent once the process exited (`stopped`) or the handover was
+    /// refused.
+    Handover {
+        stopped: bool,
+    },
+    /// Result of a `PingReq`.
+    Probe {
+        target: String,
+        alive: bool,
+    },
+    /// `admitted` is false while the listener is still verifying us with other peers.
+    Identified {
+        admitted: bool,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    UnknownRequest,
+    Unsupported,
+    Unauthenticated,
+    /// The peer isn't trusted (yet); a verification with other nodes may be in progress.
+    Unverified,
+    Internal,
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Response {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Outcome of decoding an incoming request frame.
+///
+/// Requests from newer peers may carry types we don't know yet; we still want their id
+/// so the error response can be matched up on the other side.
+pub enum Incoming {
+    Request(Envelope<Request>),
+    Invalid { id: u64, response: Response },
+}
+
+fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        bail!("Frame too large: {} bytes", payload.len());
+    }
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+fn check_frame_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        bail!("Frame too large: {} bytes", len);
+    }
+    Ok(len as usize)
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    writer.write_all(&encode_frame(message)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+
+    let mut payload = vec![0u8; check_frame_len(len)?];
+    reader.read_exact(&mut payload)?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+pub async fn write_frame_async<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> Result<()> {
+    writer.write_all(&encode_frame(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame_async<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> Result<T> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+
+    let mut payload = vec![0u8; check_frame_len(len)?];
+    reader.read_exact(&mut payload).await?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// Decodes the body of a request frame, keeping the request id even if it can't be understood.
+pub fn decode_request(raw: Envelope<serde_json::Value>) -> Incoming {
+    if raw.version < MIN_PROTOCOL_VERSION {
+        return Incoming::Invalid {
+            id: raw.id,
+            response: Response::error(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "protocol version {} is not supported (minimum {})",
+                    raw.version, MIN_PROTOCOL_VERSION
+                ),
+            ),
+        };
+    }
+
+    match serde_json::from_value::<Request>(raw.body) {
+        Ok(body) => Incoming::Request(Envelope {
+            version: raw.version,
+            id: raw.id,
+            body,
+            mac: raw.mac,
+        }),
+        Err(e) => Incoming::Invalid {
+            id: raw.id,
+            response: Response::error(ErrorCode::UnknownRequest, e.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_roundtrip_keeps_escaped_newlines() {
+        let yaml = "execution:\n  instructions: echo 'a\\nb'\n".to_string();
+        let mut buf = Vec::new();
+        write_frame(
+            &mut buf,
+            &Envelope::new(7, Response::Config { yaml: yaml.clone() }),
+        )
+        .unwrap();
+
+        let decoded: Envelope<Response> = read_frame(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.version, PROTOCOL_VERSION);
+        assert_eq!(decoded.body, Response::Config { yaml });
+    }
+
+    #[test]
+    fn test_unknown_request_keeps_id() {
+        let raw = serde_json::json!({ "version": PROTOCOL_VERSION + 1, "id": 42, "body": { "type": "from_the_future" } });
+
+        match decode_request(serde_json::from_value(raw).unwrap()) {
+            Incoming::Invalid { id, response } => {
+                assert_eq!(id, 42);
+                assert!(matches!(
+                    response,
+                    Response::Error {
+                        code: ErrorCode::UnknownRequest,
+                        ..
+                    }
+                ));
+            }
+            Incoming::Request(_) => panic!("expected an invalid request"),
+        }
+    }
+
+    #[test]
+    fn test_rejects_oversized_frame() {
+        let buf = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+        assert!(read_frame::<_, Envelope<Request>>(&mut Cursor::new(buf)).is_err());
+    }
+}
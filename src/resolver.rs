@@ -0,0 +1,184 @@
+use crate::log;
+use anyhow::{bail, Result};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long the last successful lookup of a hostname is used when the resolver fails.
+pub const RESOLVE_CACHE_TTL: Duration = Duration::from_secs(300);
+
+struct Cached {
+    addrs: Vec<IpAddr>,
+    resolved: Instant,
+    failing: bool,
+}
+
+/// Resolves node addresses, which may be IPv4/IPv6 literals or hostnames.
+///
+/// Hostnames are looked up on every attempt so DNS changes are picked up right away. The last
+/// good answer is kept to ride out resolver hiccups and to recognize incoming connections.
+#[derive(Clone, Default)]
+pub struct Resolver {
+    cache: Arc<Mutex<HashMap<String, Cached>>>,
+}
+
+/// Parses an IP literal, accepting IPv6 addresses in brackets (`[::1]`).
+pub fn parse_ip(host: &str) -> Option<IpAddr> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    host.parse().ok()
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        if let Some(ip) = parse_ip(host) {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let lookup = tokio::net::lookup_host((host, port))
+            .await
+            .map(|addrs| addrs.collect::<Vec<_>>());
+
+        let mut cache = self.cache.lock().unwrap();
+        match lookup {
+            Ok(addrs) if !addrs.is_empty() => {
+                if cache.get(host).is_some_and(|cached| cached.failing) {
+                    log!("-> Resolving \"{}\" works again", host);
+                }
+                cache.insert(
+                    host.to_string(),
+                    Cached {
+                        addrs: addrs.iter().map(|addr| addr.ip()).collect(),
+                        resolved: Instant::now(),
+                        failing: false,
+                    },
+                );
+                Ok(addrs)
+            }
+            lookup => {
+                let error = match lookup {
+                    Err(e) => e.to_string(),
+                    Ok(_) => "no addresses".to_string(),
+                };
+
+                // Only report the first failure of a streak, heartbeats retry every second
+                let cached = cache.entry(host.to_string()).or_insert(Cached {
+                    addrs: vec![],
+                    resolved: Instant::now() - RESOLVE_CACHE_TTL,
+                    failing: false,
+                });
+                if !cached.failing {
+                    log!("-> Couldn't resolve \"{}\": {}", host, error);
+                    cached.failing = true;
+                }
+
+                if cached.addrs.is_empty() || cached.resolved.elapsed() > RESOLVE_CACHE_TTL {
+                    bail!("Couldn't resolve {}: {}", host, error);
+                }
+                Ok(cached
+                    .addrs
+                    .iter()
+                    .map(|ip| SocketAddr::new(*ip, port))
+                    .collect())
+            }
+        }
+    }
+
+    /// Looks up the hostnames among `hosts` that have no fresh cache entry. Used before
+    /// deciding an incoming connection belongs to no known node.
+    pub async fn refresh(&self, hosts: Vec<(String, u16)>) {
+        let stale: Vec<_> = {
+            let cache = self.cache.lock().unwrap();
+            hosts
+                .into_iter()
+                .filter(|(host, _)| parse_ip(host).is_none())
+                .filter(|(host, _)| {
+                    cache
+                        .get(host)
+                        .is_none_or(|cached| cached.resolved.elapsed() > RESOLVE_CACHE_TTL)
+                })
+                .collect()
+        };
+        if stale.is_empty() {
+            return;
+        }
+
+        for (host, port) in stale {
+            let _ = self.resolve(&host, port).await;
+        }
+    }
+
+    /// Whether `addr` (as seen on an incoming connection) belongs to `host`. Hostnames are
+    /// only matched against earlier lookups, this never blocks on DNS.
+    pub fn matches(&self, host: &str, addr: &str) -> bool {
+        if host == addr {
+            return true;
+        }
+        let Some(addr) = parse_ip(addr).map(|ip| ip.to_canonical()) else {
+            return false;
+        };
+        if let Some(ip) = parse_ip(host) {
+            return ip.to_canonical() == addr;
+        }
+
+        self.cache.lock().unwrap().get(host).is_some_and(|cached| {
+            cached.resolved.elapsed() <= RESOLVE_CACHE_TTL
+                && cached.addrs.iter().any(|ip| ip.to_canonical() == addr)
+        })
+    }
+}
+
+/// Address to listen on, `0.0.0.0` unless `bind_address` says otherwise (e.g. `[::]`).
+pub fn bind_address(bind_address: Option<&str>, port: u16) -> String {
+    let host = bind_address.unwrap_or("0.0.0.0");
+    match parse_ip(host) {
+        Some(ip) => SocketAddr::new(ip, port).to_string(),
+        None => format!("{}:{}", host, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_literals_skip_lookup() {
+        let resolver = Resolver::new();
+        assert_eq!(
+            resolver.resolve("[::1]", 8080).await.unwrap(),
+            vec!["[::1]:8080".parse().unwrap()]
+        );
+        assert_eq!(
+            resolver.resolve("10.0.0.1", 8080).await.unwrap(),
+            vec!["10.0.0.1:8080".parse().unwrap()]
+        );
+        assert!(resolver.matches("::1", "::1"));
+        assert!(resolver.matches("10.0.0.1", "::ffff:10.0.0.1"));
+        assert!(!resolver.matches("10.0.0.1", "10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_hostnames_are_matched_after_lookup() {
+        let resolver = Resolver::new();
+        assert!(!resolver.matches("localhost", "127.0.0.1"));
+        let addrs = resolver.resolve("localhost", 8080).await.unwrap();
+        assert!(resolver.matches("localhost", &addrs[0].ip().to_string()));
+    }
+
+    #[test]
+    fn test_bind_address() {
+        assert_eq!(bind_address(None, 8080), "0.0.0.0:8080");
+        assert_eq!(bind_address(Some("::"), 8080), "[::]:8080");
+        assert_eq!(bind_address(Some("[::]"), 8080), "[::]:8080");
+        assert_eq!(bind_address(Some("localhost"), 8080), "localhost:8080");
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{
+    config::{ExecutionInstructions, Service, StopPolicy},
+    event::{Event, Events},
+    log,
+    process::Process,
+};
+use anyhow::Result;
+use std::{os::unix::process::ExitStatusExt, time::Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    Running,
+    /// Waiting to restart the process after it exited
+    Restarting,
+    /// The process failed more often than the restart policy allows
+    GaveUp,
+    /// Some of the process group survived stopping it. It's not restarted, only stopped again.
+    Stopping,
+}
+
+/// Keeps the process of a service running while we're active: reaps it when it exits and restarts it with
+/// an exponential backoff.
+pub struct Supervisor {
+    service: String,
+    process: Option<Process>,
+    started_at: Instant,
+    /// Restarts in a row so far
+    restarts: u32,
+    restart_at: Option<Instant>,
+    /// Stopping the process failed, so it's kept around to try again
+    stopping: bool,
+    events: Events,
+}
+
+impl Supervisor {
+    /// Starts the process right away.
+    pub fn start(service: &Service, events: Events) -> Supervisor {
+        let mut supervisor = Supervisor {
+            service: service.name.clone(),
+            process: None,
+            started_at: Instant::now(),
+            restarts: 0,
+            restart_at: None,
+            stopping: false,
+            events,
+        };
+        supervisor.spawn(&service.execution, Instant::now());
+        supervisor
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.process.as_ref().map(|p| p.child.id())
+    }
+
+    /// Restarts in a row so far
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Restarts the process if it exited and its backoff is over. Meant to be called every
+    /// heartbeat.
+    pub fn check(&mut self, execution: &ExecutionInstructions, now: Instant) -> Health {
+        if self.stopping {
+            return Health::Stopping;
+        }
+        let policy = &execution.restart;
+        if let Some(process) = &mut self.process {
+            let Some(status) = process.try_wait() else {
+                return Health::Running;
+            };
+            let event = Event::ProcessExited {
+                pid: process.child.id(),
+                code: status.code(),
+                signal: status.signal(),
+            };
+            self.events.emit(&self.service, event);
+            process.kill_leftovers();
+            self.process = None;
+            if now.duration_since(self.started_at) >= policy.reset_after() {
+                self.restarts = 0;
+            }
+            return self.schedule_restart(execution, now);
+        }
+
+        match self.restart_at {
+            Some(at) if now >= at => {
+                self.restarts += 1;
+                log!(
+                    "-> Restarting {} ({} of {})",
+                    self.service,
+                    self.restarts,
+                    policy.max_restarts
+                );
+                self.spawn(execution, now)
+            }
+            Some(_) => Health::Restarting,
+            None => Health::GaveUp,
+        }
+    }
+
+    /// Stops the process, if it's running, and cancels any pending restart. Blocks until
+    /// its process group is gone. If some of it survives, the process is kept so stopping can
+    /// be tried again.
+    pub fn stop(&mut self, policy: &StopPolicy) -> Result<()> {
+        self.restart_at = None;
+        if let Some(process) = &mut self.process {
+            if let Err(e) = process.stop(policy) {
+                self.stopping = true;
+                return Err(e);
+            }
+        }
+        self.process = None;
+        self.stopping = false;
+        Ok(())
+    }
+
+    /// Kills the process group right away, without waiting for it. The process isn't
+    /// restarted, only stopped again.
+    pub fn kill(&mut self) {
+        self.restart_at = None;
+        if let Some(process) = &self.process {
+            process.kill();
+            self.stopping = true;
+        }
+    }
+
+    fn spawn(&mut self, execution: &ExecutionInstructions, now: Instant) -> Health {
+        match Process::new(&self.service, execution) {
+            Ok(process) => {
+                self.process = Some(process);
+                self.started_at = now;
+                self.restart_at = None;
+                Health::Running
+            }
+            Err(e) => {
+                let event = Event::SpawnFailed {
+                    error: format!("{:#}", e),
+                };
+                self.events.emit(&self.service, event);
+                self.schedule_restart(execution, now)
+            }
+        }
+    }
+
+    fn schedule_restart(&mut self, execution: &ExecutionInstructions, now: Instant) -> Health {
+        let policy = &execution.restart;
+        if self.restarts >= policy.max_restarts {
+            self.restart_at = None;
+            return Health::GaveUp;
+        }
+        let backoff = policy.backoff(self.restarts + 1);
+        log!(
+            "-> Restarting {} in {}ms",
+            self.service,
+            backoff.as_millis()
+        );
+        self.restart_at = Some(now + backoff);
+        Health::Restarting
+    }
+}
+
+/// Processes are stopped through `stop`, which waits for them. Dropping a supervisor that
+/// still has one is a last resort and mustn't block, so it only kills the group.
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        if let Some(process) = &self.process {
+            log!(
+                "Killing process group {} of {}, which wasn't stopped",
+                process.child.id(),
+                self.service
+            );
+            process.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::Duration;
+
+    fn service(instructions: &str) -> Service {
+        let yaml = format!(
+            r#"
+nodes: []
+config_metadata:
+  name: a
+  last_updated: 2024-03-20 00:00:00 UTC
+execution:
+  instructions: {}
+  last_updated: 2024-03-20 00:00:00 UTC
+  restart:
+    max_restarts: 2
+    initial_backoff_ms: 100
+"#,
+            instructions
+        );
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        config.services().remove(0)
+    }
+
+    /// Checks until the process isn't running anymore
+    fn exited(supervisor: &mut Supervisor, execution: &ExecutionInstructions) -> Health {
+        for _ in 0..100 {
+            match supervisor.check(execution, Instant::now()) {
+                Health::Running => std::thread::sleep(Duration::from_millis(10)),
+                health => return health,
+            }
+        }
+        panic!("The process never exited");
+    }
+
+    #[test]
+    fn test_gives_up_after_max_restarts() {
+        let service = service("false");
+        let events = Events::new();
+        let mut supervisor = Supervisor::start(&service, events.clone());
+
+        assert_eq!(
+            exited(&mut supervisor, &service.execution),
+            Health::Restarting
+        );
+        // Still backing off
+        assert_eq!(
+            supervisor.check(&service.execution, Instant::now()),
+            Health::Restarting
+        );
+        let later = Instant::now() + Duration::from_millis(100);
+        assert_eq!(supervisor.check(&service.execution, later), Health::Running);
+        assert_eq!(supervisor.restarts(), 1);
+
+        assert_eq!(
+            exited(&mut supervisor, &service.execution),
+            Health::Restarting
+        );
+        let later = Instant::now() + Duration::from_millis(200);
+        assert_eq!(supervisor.check(&service.execution, later), Health::Running);
+        assert_eq!(exited(&mut supervisor, &service.execution), Health::GaveUp);
+
+        let recent = events.recent();
+        assert_eq!(recent.len(), 3);
+        assert!(matches!(
+            recent[0].event,
+            Event::ProcessExited { code: Some(1), .. }
+        ));
+    }
+
+    #[test]
+    fn test_missing_binary_is_restarted() {
+        let service = service("/nonexistent/binary");
+        let events = Events::new();
+        let mut supervisor = Supervisor::start(&service, events.clone());
+
+        assert_eq!(supervisor.pid(), None);
+        assert_eq!(
+            supervisor.check(&service.execution, Instant::now()),
+            Health::Restarting
+        );
+        assert!(matches!(
+            events.recent()[0].event,
+            Event::SpawnFailed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_failed_stop_keeps_the_process() {
+        let service = service("sleep 100");
+        // So the zombie is ours to reap once its parent is gone
+        crate::process::become_subreaper();
+        let mut supervisor = Supervisor::start(&service, Events::new());
+        let pgid = supervisor.pid().unwrap() as libc::pid_t;
+
+        // A zombie in the group whose parent is outside of it: it can't be killed or reaped
+        // by us, so the group never goes away
+        // SAFETY: the children only make async-signal-safe calls before exiting or pausing
+        let parent = unsafe {
+            let parent = libc::fork();
+            if parent == 0 {
+                libc::setpgid(0, 0);
+                if libc::fork() == 0 {
+                    libc::setpgid(0, pgid);
+                    libc::_exit(0);
+                }
+                loop {
+                    libc::pause();
+                }
+            }
+            parent
+        };
+        std::thread::sleep(Duration::from_millis(200));
+
+        let policy = StopPolicy {
+            grace_period_ms: 100,
+            ..Default::default()
+        };
+        assert!(supervisor.stop(&policy).is_err());
+        assert!(supervisor.pid().is_some());
+        assert_eq!(
+            supervisor.check(&service.execution, Instant::now()),
+            Health::Stopping
+        );
+
+        // SAFETY: kill and waitpid on our own child
+        unsafe {
+            libc::kill(parent, libc::SIGKILL);
+            libc::waitpid(parent, std::ptr::null_mut(), 0);
+        }
+        supervisor.stop(&policy).unwrap();
+        assert_eq!(supervisor.pid(), None);
+    }
+}
@@ -1,59 +1,572 @@
+use crate::auth::{self, ClusterAuth, Role, Session};
 use crate::config::Config;
+use crate::lease::ServiceLeases;
+use crate::membership::Membership;
+use crate::node::{Handover, Node, HANDOVER_TIMEOUT};
+use crate::node_connections::NodeConnections;
+use crate::pending_verification::{self, PendingVerifications, Trust};
+use crate::phi::PhiAccrual;
+use crate::protocol::{self, Envelope, ErrorCode, Incoming, Request, Response};
+use crate::resolver;
+use crate::tls;
 use crate::{debug, log};
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use rustls::ServerConfig;
+use serde_json::Value;
+use std::io::ErrorKind;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
 
-pub fn start_tcp_listener(config: Arc<Mutex<Config>>, config_string: Arc<Mutex<String>>) {
-    thread::spawn(move || {
-        let config = config.clone();
+/// Connections that stay silent for this long are closed. Peers ping every second, so a
+/// healthy connection never gets close to it.
+pub const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
-        let port = {
+/// How long a peer gets to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared by all connections of the listener.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Mutex<Config>>,
+    node_connections: NodeConnections,
+    pending: PendingVerifications,
+    membership: Membership,
+    leases: ServiceLeases,
+    phi: PhiAccrual,
+    /// Handover requests, answered by whoever owns the node
+    handovers: mpsc::Sender<Handover>,
+    wake: Arc<Notify>,
+}
+
+/// Who is on the other end of a connection.
+struct Peer {
+    remote_addr: String,
+    /// Node name proven by a TLS certificate
+    verified: Option<String>,
+    /// Node name the peer says it is
+    claimed: Option<String>,
+}
+
+/// Answers peers on behalf of `node`. Handover requests are forwarded to `handovers`.
+pub fn start_tcp_listener(
+    tls: Option<Arc<ServerConfig>>,
+    node: &Node,
+    handovers: mpsc::Sender<Handover>,
+) {
+    let config = node.config.clone();
+    let acceptor = tls.map(TlsAcceptor::from);
+    let shared = Shared {
+        config: config.clone(),
+        node_connections: node.node_connections.clone(),
+        pending: node.pending_verifications.clone(),
+        membership: node.membership.clone(),
+        leases: node.leases.clone(),
+        phi: node.phi.clone(),
+        handovers,
+        wake: node.wake.clone(),
+    };
+
+    tokio::spawn(async move {
+        let (port, address) = {
             let cfg = config.lock().unwrap();
             let self_name = &cfg.config_metadata.name;
-            cfg.nodes.iter().find(|d| d.name == *self_name).unwrap().port
+            let port = cfg
+                .nodes
+                .iter()
+                .find(|d| d.name == *self_name)
+                .unwrap()
+                .port;
+            (
+                port,
+                resolver::bind_address(cfg.bind_address.as_deref(), port as u16),
+            )
         };
 
-        let listener = match TcpListener::bind(format!("0.0.0.0:{}", port)) {
+        let listener = match TcpListener::bind(&address).await {
             Ok(listener) => listener,
             Err(error) => {
-                panic!("TcpListener can't bind to port {port}, {:?}", error);
+                panic!("TcpListener can't bind to {address}, {:?}", error);
             }
         };
 
         log!("Rocking on port {port}!");
+        debug!("Listening on {}", address);
+
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log!("Error accepting connection: {:?}", e);
+                    continue;
+                }
+            };
+            debug!("Connection established with {}", remote_addr);
+
+            let shared = shared.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let peer = Peer {
+                    // IPv4 peers show up as `::ffff:a.b.c.d` on dual-stack sockets
+                    remote_addr: remote_addr.ip().to_canonical().to_string(),
+                    verified: None,
+                    claimed: None,
+                };
+                match acceptor {
+                    Some(acceptor) => accept_tls(acceptor, stream, peer, shared).await,
+                    None => handle_connection(stream, peer, shared).await,
+                }
+                debug!("Connection with {} closed", remote_addr);
+            });
+        }
+    });
+}
+
+/// Completes the TLS handshake and only serves peers whose certificate names a configured node.
+async fn accept_tls(acceptor: TlsAcceptor, stream: TcpStream, mut peer: Peer, shared: Shared) {
+    let remote_addr = peer.remote_addr.clone();
+    let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            log!("TLS handshake with {} failed: {:?}", remote_addr, e);
+            return;
+        }
+        Err(_) => {
+            log!("TLS handshake with {} timed out", remote_addr);
+            return;
+        }
+    };
+
+    let verified = stream.get_ref().1.peer_certificates().and_then(|certs| {
+        let config = shared.config.lock().unwrap();
+        tls::identify_peer(certs, &config.nodes)
+    });
+    match verified {
+        Some(name) => {
+            debug!("{} authenticated as \"{}\"", remote_addr, name);
+            peer.verified = Some(name);
+            handle_connection(stream, peer, shared).await;
+        }
+        None => log!(
+            "Rejecting {}: certificate doesn't match any configured node",
+            remote_addr
+        ),
+    }
+}
+
+/// Where a connection is in the cluster-secret handshake.
+enum AuthState {
+    /// No cluster secret configured
+    Disabled,
+    AwaitingHello(ClusterAuth),
+    AwaitingProof {
+        auth: ClusterAuth,
+        peer: String,
+        client_nonce: String,
+        server_nonce: String,
+    },
+    Authenticated(Session),
+}
 
-        for stream in listener.incoming() {
-            debug!("Connection established");
+impl AuthState {
+    fn new(config: &Arc<Mutex<Config>>) -> AuthState {
+        match ClusterAuth::from_config(&config.lock().unwrap()) {
+            Some(auth) => AuthState::AwaitingHello(auth),
+            None => AuthState::Disabled,
+        }
+    }
 
-            if let Ok(stream) = stream {
-                let reader = BufReader::new(&stream);
-                let mut writer = &stream;
+    fn session(&mut self) -> Option<&mut Session> {
+        match self {
+            AuthState::Authenticated(session) => Some(session),
+            _ => None,
+        }
+    }
+}
 
-                for line in reader.lines().map_while(Result::ok) {
-                    let remote_addr = stream.peer_addr().unwrap().ip().to_string();
-                    let line = line.as_str();
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    mut peer: Peer,
+    shared: Shared,
+) {
+    let remote_addr = peer.remote_addr.clone();
+    let remote_addr = remote_addr.as_str();
+    let mut auth = AuthState::new(&shared.config);
 
-                    debug!(
-                        "Received line: {:?} (l:{}) from {}",
-                        line,
-                        line.len(),
-                        remote_addr
+    loop {
+        let raw = match timeout(
+            CONNECTION_IDLE_TIMEOUT,
+            protocol::read_frame_async::<_, Envelope<Value>>(&mut stream),
+        )
+        .await
+        {
+            Ok(Ok(raw)) => raw,
+            Ok(Err(e)) => {
+                if !is_disconnect(&e) {
+                    log!("Error reading from {}: {:#}", remote_addr, e);
+                }
+                if let AuthState::AwaitingProof { peer, .. } = &auth {
+                    log!(
+                        "Rejecting {} (\"{}\"): disconnected before authenticating",
+                        remote_addr,
+                        peer
                     );
+                }
+                return;
+            }
+            Err(_) => {
+                log!("Closing idle connection from {}", remote_addr);
+                return;
+            }
+        };
 
-                    if line.len() == 4 && &line[0..4] == "PING" {
-                        let _ = writer.write_all(b"PONG\n");
-                        let _ = writer.flush();
-                    } else if line.len() >= 10 && &line[0..10] == "GET CONFIG" {
-                        let config_str = config_string.lock().unwrap();
-                        let _ = writer
-                            .write_all(format!("{}\n", config_str.replace("\n", "\\n")).as_bytes());
-                        let _ = writer.flush();
-                        debug!("Sent config to {}", remote_addr);
+        if let Some(session) = auth.session() {
+            if let Err(e) = session.open(&raw) {
+                log!("Rejecting message from {}: {}", remote_addr, e);
+                return;
+            }
+        }
+
+        let (id, response) = match protocol::decode_request(raw) {
+            Incoming::Request(request) => {
+                debug!("Received {:?} from {}", request, remote_addr);
+                if let Request::Hello { node, .. } | Request::Identify { node } = &request.body {
+                    peer.claimed = Some(node.clone());
+                }
+
+                let response = match authenticate(&mut auth, request.body) {
+                    Ok(Some(body)) => handle_request(&body, &mut peer, &shared).await,
+                    Ok(None) => continue_handshake(&mut auth),
+                    Err(response) => {
+                        log!("Rejecting unauthenticated peer {}", remote_addr);
+                        let _ = respond(&mut stream, &mut auth, request.id, response).await;
+                        return;
                     }
+                };
+                (request.id, response)
+            }
+            Incoming::Invalid { id, response } => {
+                log!(
+                    "Rejected request {} from {}: {:?}",
+                    id,
+                    remote_addr,
+                    response
+                );
+                (id, response)
+            }
+        };
+
+        if let Err(e) = respond(&mut stream, &mut auth, id, response).await {
+            debug!("Error writing to {}: {:#}", remote_addr, e);
+            return;
+        }
+    }
+}
+
+/// Runs `request` through the handshake state machine.
+///
+/// Returns the request if it may be served, `None` if it was a handshake step (answered by
+/// `continue_handshake`), or the error to send before dropping the peer.
+fn authenticate(auth: &mut AuthState, request: Request) -> Result<Option<Request>, Response> {
+    let unauthenticated = |message: &str| Response::error(ErrorCode::Unauthenticated, message);
+
+    match (std::mem::replace(auth, AuthState::Disabled), request) {
+        (AuthState::Disabled, request) => Ok(Some(request)),
+        (AuthState::Authenticated(session), request) => {
+            *auth = AuthState::Authenticated(session);
+            Ok(Some(request))
+        }
+        (AuthState::AwaitingHello(cluster), Request::Hello { node, nonce }) => {
+            let server_nonce = auth::nonce().map_err(|_| unauthenticated("handshake failed"))?;
+            *auth = AuthState::AwaitingProof {
+                auth: cluster,
+                peer: node,
+                client_nonce: nonce,
+                server_nonce,
+            };
+            Ok(None)
+        }
+        (
+            AuthState::AwaitingProof {
+                auth: cluster,
+                peer,
+                client_nonce,
+                server_nonce,
+            },
+            Request::Authenticate { proof },
+        ) => {
+            if !cluster.verify_client_proof(&client_nonce, &server_nonce, &proof) {
+                return Err(unauthenticated("invalid proof"));
+            }
+            debug!("\"{}\" proved the cluster secret", peer);
+            *auth = AuthState::Authenticated(cluster.session(
+                &client_nonce,
+                &server_nonce,
+                Role::Server,
+            ));
+            Ok(None)
+        }
+        _ => Err(unauthenticated("authentication required")),
+    }
+}
+
+fn continue_handshake(auth: &mut AuthState) -> Response {
+    match auth {
+        AuthState::AwaitingProof {
+            auth,
+            client_nonce,
+            server_nonce,
+            ..
+        } => Response::Challenge {
+            proof: auth.server_proof(client_nonce, server_nonce),
+            nonce: server_nonce.clone(),
+        },
+        _ => Response::Authenticated,
+    }
+}
+
+async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: &mut AuthState,
+    id: u64,
+    response: Response,
+) -> anyhow::Result<()> {
+    let envelope = Envelope::new(id, response);
+    match auth.session() {
+        Some(session) => protocol::write_frame_async(stream, &session.seal(envelope)?).await,
+        None => protocol::write_frame_async(stream, &envelope).await,
+    }
+}
+
+async fn handle_request(request: &Request, peer: &mut Peer, shared: &Shared) -> Response {
+    match request {
+        Request::Ping => Response::Pong,
+        Request::Gossip {
+            updates,
+            config_version,
+            term,
+            terms,
+        } => {
+            let peer_name = match require_trust(peer, shared).await {
+                Ok(name) => name,
+                Err(response) => return response,
+            };
+
+            shared.phi.heartbeat(&peer_name);
+            shared.leases.observe(*term, terms);
+            let joined = shared.membership.apply(updates.clone());
+            pending_verification::verify_joined(
+                &shared.pending,
+                &shared.node_connections,
+                &shared.config,
+                joined,
+            );
+
+            let local_version = shared
+                .config
+                .lock()
+                .unwrap()
+                .config_metadata
+                .last_updated
+                .clone();
+            if *config_version > local_version {
+                let node_connections = shared.node_connections.clone();
+                let config = shared.config.clone();
+                tokio::spawn(async move {
+                    node_connections
+                        .update_config_from(&peer_name, config)
+                        .await
+                });
+            }
+
+            let (term, terms) = shared.leases.terms();
+            Response::Gossip {
+                updates: shared.membership.piggyback(),
+                config_version: local_version,
+                term,
+                terms,
+            }
+        }
+        Request::Lease { term, service } => {
+            let candidate = match require_trust(peer, shared).await {
+                Ok(name) => name,
+                Err(response) => return response,
+            };
+            let vote = shared.leases.get(service).vote(&candidate, *term);
+            debug!(
+                "Lease of {} for \"{}\" in term {}: {:?}",
+                service, candidate, term, vote
+            );
+            Response::Lease {
+                granted: vote.granted,
+                term: vote.term,
+                holder: vote.holder,
+            }
+        }
+        Request::Release { term, service } => {
+            let holder = match require_trust(peer, shared).await {
+                Ok(name) => name,
+                Err(response) => return response,
+            };
+            shared.leases.get(service).release(&holder, *term);
+            if shared.membership.record_stepped_down(&holder, service) {
+                // Someone may have to take over, no need to wait for the next heartbeat
+                shared.wake.notify_one();
+            }
+            Response::Released
+        }
+        Request::Handover {
+            preference,
+            service,
+        } => {
+            let from = match require_trust(peer, shared).await {
+                Ok(name) => name,
+                Err(response) => return response,
+            };
+            let (reply, stopped) = oneshot::channel();
+            let handover = Handover {
+                from: from.clone(),
+                service: service.clone(),
+                preference: *preference,
+                reply,
+            };
+            if shared.handovers.send(handover).await.is_err() {
+                return Response::error(ErrorCode::Internal, "the node is shutting down");
+            }
+            match timeout(HANDOVER_TIMEOUT, stopped).await {
+                Ok(Ok(stopped)) => Response::Handover { stopped },
+                _ => Response::error(
+                    ErrorCode::Internal,
+                    format!("couldn't hand over to {}", from),
+                ),
+            }
+        }
+        Request::PingReq { target } => {
+            if let Err(response) = require_trust(peer, shared).await {
+                return response;
+            }
+            let node = {
+                let config = shared.config.lock().unwrap();
+                config
+                    .nodes
+                    .iter()
+                    .find(|node| node.name == *target)
+                    .cloned()
+            };
+            let Some(node) = node else {
+                return Response::error(ErrorCode::Internal, format!("unknown node {}", target));
+            };
+
+            let alive = shared.node_connections.ping(&node).await;
+            Response::Probe {
+                target: target.clone(),
+                alive,
+            }
+        }
+        Request::Identify { .. } => Response::Identified {
+            admitted: require_trust(peer, shared).await.is_ok(),
+        },
+        Request::GetConfig => {
+            if let Err(response) = require_trust(peer, shared).await {
+                return response;
+            }
+            match shared.config.lock().unwrap().to_shared_yaml() {
+                Ok(yaml) => Response::Config { yaml },
+                Err(e) => Response::error(ErrorCode::Internal, e.to_string()),
+            }
+        }
+        Request::Confirm { source, is_ip } => {
+            if let Err(response) = require_trust(peer, shared).await {
+                return response;
+            }
+            let resolver = shared.node_connections.resolver();
+            let confirmed = shared.config.lock().unwrap().nodes.iter().any(|node| {
+                if *is_ip {
+                    resolver.matches(&node.ip, source)
+                } else {
+                    node.name == *source
                 }
+            });
+            Response::Confirm {
+                source: source.clone(),
+                is_ip: *is_ip,
+                confirmed,
             }
         }
-    });
+        Request::Hello { .. } | Request::Authenticate { .. } => {
+            Response::error(ErrorCode::Unsupported, "unexpected handshake message")
+        }
+    }
+}
+
+/// Only configured nodes get our config or may ask us to vouch for others. Anyone else is
+/// verified with our peers in the background and refused until that succeeds.
+async fn require_trust(peer: &Peer, shared: &Shared) -> Result<String, Response> {
+    if let Some(name) = &peer.verified {
+        return Ok(name.clone());
+    }
+
+    let classify = || {
+        pending_verification::classify(
+            &shared.config.lock().unwrap(),
+            shared.node_connections.resolver(),
+            &peer.remote_addr,
+            peer.claimed.as_deref(),
+        )
+    };
+    let trust = match classify() {
+        Trust::Trusted(name) => Trust::Trusted(name),
+        Trust::Unverified { .. } => {
+            // Nodes configured by hostname may not have been looked up yet
+            let hosts = shared
+                .config
+                .lock()
+                .unwrap()
+                .nodes
+                .iter()
+                .map(|node| (node.ip.clone(), node.port as u16))
+                .collect();
+            shared.node_connections.resolver().refresh(hosts).await;
+            classify()
+        }
+    };
+    let (source, is_ip) = match trust {
+        Trust::Trusted(name) => return Ok(name),
+        Trust::Unverified { source, is_ip } => (source, is_ip),
+    };
+
+    if shared.pending.start(&source, &peer.remote_addr, is_ip) {
+        log!(
+            "Verifying unknown peer {} ({}) with other nodes",
+            source,
+            peer.remote_addr
+        );
+        let shared = shared.clone();
+        let (source, remote_addr) = (source.clone(), peer.remote_addr.clone());
+        tokio::spawn(pending_verification::verify(
+            shared.pending,
+            shared.node_connections,
+            shared.config,
+            source,
+            remote_addr,
+            is_ip,
+        ));
+    }
+
+    Err(Response::error(
+        ErrorCode::Unverified,
+        format!("{} is not verified", source),
+    ))
+}
+
+fn is_disconnect(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset
+        )
+    })
 }
@@ -43,3 +43,9 @@ impl<'de> Deserialize<'de> for Timestamp {
         Ok(Timestamp(dt))
     }
 }
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0 .0)
+    }
+}
@@ -0,0 +1,207 @@
+use crate::config::{Config, ProviderNode, TlsConfig};
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use std::{fs::File, io::BufReader, sync::Arc};
+
+/// Client and server halves of this node's mutual TLS setup.
+#[derive(Clone)]
+pub struct TlsContext {
+    pub client: Arc<ClientConfig>,
+    pub server: Arc<ServerConfig>,
+}
+
+impl TlsContext {
+    /// Loads the certificates referenced by `config.tls`, or returns `None` if TLS is off.
+    pub fn from_config(config: &Config) -> Result<Option<TlsContext>> {
+        match &config.tls {
+            Some(tls) => Ok(Some(TlsContext::load(tls)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load(tls: &TlsConfig) -> Result<TlsContext> {
+        let provider = Arc::new(ring::default_provider());
+        let roots = Arc::new(load_roots(&tls.ca)?);
+        let certs = load_certs(&tls.cert)?;
+        let key = load_key(&tls.key)?;
+
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots.clone())
+            .with_client_auth_cert(certs.clone(), key.clone_key())
+            .context("Invalid client certificate")?;
+
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(roots, provider.clone()).build()?;
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .context("Invalid server certificate")?;
+
+        Ok(TlsContext {
+            client: Arc::new(client),
+            server: Arc::new(server),
+        })
+    }
+}
+
+/// The name a peer's certificate must be issued for.
+pub fn server_name(node: &ProviderNode) -> Result<ServerName<'static>> {
+    ServerName::try_from(node.name.clone())
+        .map_err(|_| anyhow!("Node name {:?} can't be used as a TLS name", node.name))
+}
+
+/// Finds the configured node whose name the (already CA-verified) peer certificate is valid
+/// for.
+pub fn identify_peer(certs: &[CertificateDer<'_>], nodes: &[ProviderNode]) -> Option<String> {
+    let cert = webpki::EndEntityCert::try_from(certs.first()?).ok()?;
+
+    nodes
+        .iter()
+        .find(|node| {
+            server_name(node)
+                .map(|name| cert.verify_is_valid_for_subject_name(&name).is_ok())
+                .unwrap_or(false)
+        })
+        .map(|node| node.name.clone())
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("Opening {path}"))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("No certificates found in {path}");
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("Opening {path}"))?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| anyhow!("No private key in {path}"))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::NodeRole, timestamp::Timestamp};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{ClientConnection, ServerConnection};
+    use std::{net::TcpListener, path::Path, thread};
+
+    struct Ca {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    fn new_ca(dir: &Path, file: &str) -> Ca {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        std::fs::write(dir.join(file), cert.pem()).unwrap();
+        Ca { cert, key }
+    }
+
+    fn new_node(dir: &Path, ca: &Ca, ca_file: &str, name: &str) -> TlsConfig {
+        let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca.cert, &ca.key).unwrap();
+
+        let cert_path = dir.join(format!("{name}.crt"));
+        let key_path = dir.join(format!("{name}.key"));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+
+        TlsConfig {
+            cert: cert_path.to_string_lossy().into_owned(),
+            key: key_path.to_string_lossy().into_owned(),
+            ca: dir.join(ca_file).to_string_lossy().into_owned(),
+        }
+    }
+
+    fn node(name: &str) -> ProviderNode {
+        ProviderNode {
+            name: name.to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: 0,
+            priority: 0,
+            last_updated: Timestamp::now(),
+            role: NodeRole::Provider,
+            id: None,
+        }
+    }
+
+    /// Runs a handshake from `client` to a server using `server`, returning the peer name the
+    /// server identified, or `None` if the handshake failed.
+    fn handshake(server: &TlsContext, client: &TlsContext, server_node: &str) -> Option<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_config = server.server.clone();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut conn = ServerConnection::new(server_config).unwrap();
+            while conn.is_handshaking() {
+                conn.complete_io(&mut stream).ok()?;
+            }
+            identify_peer(conn.peer_certificates()?, &[node("a"), node("b")])
+        });
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let name = server_name(&node(server_node)).unwrap();
+        let mut conn = ClientConnection::new(client.client.clone(), name).unwrap();
+        while conn.is_handshaking() {
+            if conn.complete_io(&mut stream).is_err() {
+                break;
+            }
+        }
+        drop(stream);
+
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn test_mutual_tls_identifies_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = new_ca(dir.path(), "ca.crt");
+        let a = TlsContext::load(&new_node(dir.path(), &ca, "ca.crt", "a")).unwrap();
+        let b = TlsContext::load(&new_node(dir.path(), &ca, "ca.crt", "b")).unwrap();
+
+        assert_eq!(handshake(&a, &b, "a"), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_unknown_node_name_is_not_identified() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = new_ca(dir.path(), "ca.crt");
+        let a = TlsContext::load(&new_node(dir.path(), &ca, "ca.crt", "a")).unwrap();
+        let mallory = TlsContext::load(&new_node(dir.path(), &ca, "ca.crt", "mallory")).unwrap();
+
+        assert_eq!(handshake(&a, &mallory, "a"), None);
+    }
+
+    #[test]
+    fn test_foreign_ca_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = new_ca(dir.path(), "ca.crt");
+        let other_ca = new_ca(dir.path(), "other-ca.crt");
+        let a = TlsContext::load(&new_node(dir.path(), &ca, "ca.crt", "a")).unwrap();
+        let b = TlsContext::load(&new_node(dir.path(), &other_ca, "other-ca.crt", "b")).unwrap();
+
+        assert_eq!(handshake(&a, &b, "a"), None);
+    }
+}